@@ -0,0 +1,139 @@
+use crate::{BlockHash, BlockHeight, BlockHeightAndHash};
+use bitcoin::consensus::{encode, Decodable, Encodable};
+use std::io::{Read, Write};
+
+/// Number of most recent blocks included one by one,
+/// before the spacing between entries starts doubling
+const DENSE_LEN: usize = 10;
+
+/// A list of block hashes of a chain, spaced exponentially
+///
+/// Just like block locators in the Bitcoin P2P protocol: the most recent
+/// blocks one by one, then doubling the distance between them all the way
+/// down to the lowest known block. It allows finding a fork point
+/// with another chain without knowing in advance how deep the reorg was,
+/// while staying small enough to be persisted after every block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChainLocator {
+    /// Entries in descending height order
+    entries: Vec<BlockHeightAndHash>,
+}
+
+impl ChainLocator {
+    /// Build a locator from the known blocks of a chain
+    ///
+    /// `known` must be in descending height order. Known blocks don't have to
+    /// be contiguous: for every desired height the closest known block below it
+    /// is used.
+    pub fn from_descending(known: impl IntoIterator<Item = BlockHeightAndHash>) -> Self {
+        let mut entries = vec![];
+        let mut step = 1;
+        let mut wanted: Option<BlockHeight> = None;
+
+        for entry in known {
+            if wanted.map(|wanted| wanted < entry.height).unwrap_or(false) {
+                continue;
+            }
+            entries.push(entry);
+            if entry.height == 0 {
+                break;
+            }
+            if DENSE_LEN <= entries.len() {
+                step *= 2;
+            }
+            wanted = Some(entry.height.saturating_sub(step));
+        }
+
+        Self { entries }
+    }
+
+    /// The highest block in the locator
+    pub fn tip(&self) -> Option<&BlockHeightAndHash> {
+        self.entries.first()
+    }
+
+    /// All the entries, in descending height order
+    pub fn entries(&self) -> &[BlockHeightAndHash] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Encodable for ChainLocator {
+    fn consensus_encode<W: Write>(&self, mut writer: W) -> Result<usize, std::io::Error> {
+        let mut written = 0;
+        written += (self.entries.len() as u32).consensus_encode(&mut writer)?;
+        for entry in &self.entries {
+            written += entry.height.consensus_encode(&mut writer)?;
+            written += entry.hash.consensus_encode(&mut writer)?;
+        }
+        Ok(written)
+    }
+}
+
+impl Decodable for ChainLocator {
+    fn consensus_decode<D: Read>(mut d: D) -> Result<Self, encode::Error> {
+        let len = u32::consensus_decode(&mut d)?;
+        // don't trust the length of a possibly corrupted locator for the allocation
+        let mut entries = Vec::with_capacity(len.min(1 << 10) as usize);
+        for _ in 0..len {
+            entries.push(BlockHeightAndHash {
+                height: Decodable::consensus_decode(&mut d)?,
+                hash: Decodable::consensus_decode(&mut d)?,
+            });
+        }
+        Ok(Self { entries })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ChainLocator;
+    use crate::{BlockHash, BlockHeightAndHash, Hash};
+    use bitcoin::consensus::{deserialize, serialize};
+
+    fn block(height: u32) -> BlockHeightAndHash {
+        BlockHeightAndHash {
+            height,
+            hash: BlockHash::hash(&height.to_le_bytes()),
+        }
+    }
+
+    #[test]
+    fn locator_spacing() {
+        let locator = ChainLocator::from_descending((0..=1000).rev().map(block));
+        let heights: Vec<_> = locator.entries().iter().map(|e| e.height).collect();
+        assert_eq!(
+            heights,
+            vec![
+                1000, 999, 998, 997, 996, 995, 994, 993, 992, 991, 989, 985, 977, 961, 929, 865,
+                737, 481, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn locator_sparse() {
+        let locator = ChainLocator::from_descending([100, 50, 20, 7, 0].iter().copied().map(block));
+        let heights: Vec<_> = locator.entries().iter().map(|e| e.height).collect();
+        assert_eq!(heights, vec![100, 50, 20, 7, 0]);
+    }
+
+    #[test]
+    fn locator_round_trip() {
+        let locator = ChainLocator::from_descending((0..=100).rev().map(block));
+        let ser = serialize(&locator);
+        let deser: ChainLocator = deserialize(&ser).unwrap();
+        assert_eq!(locator, deser);
+    }
+
+    #[test]
+    fn locator_corrupted_len() {
+        let mut ser = serialize(&ChainLocator::from_descending((0..=100).rev().map(block)));
+        ser[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(deserialize::<ChainLocator>(&ser).is_err());
+    }
+}
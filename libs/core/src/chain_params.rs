@@ -0,0 +1,74 @@
+use crate::{BlockHash, Hash as _, Sha256dHash};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::serialize;
+use bitcoin::{Network, Script};
+
+/// Default for [`ChainParams::max_reorg_hint`]
+const DEFAULT_MAX_REORG_HINT: u8 = 6;
+
+/// What's needed to read the blocks of a chain
+///
+/// Built from a `bitcoin::Network` for the standard networks, or by hand for custom
+/// signets, private regtest forks and other chains sharing the Bitcoin block format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainParams {
+    /// Magic prefixing every record in blk files, as returned by `Network::magic`
+    pub magic: u32,
+    /// Hash of the first block of the chain
+    pub genesis_hash: BlockHash,
+    /// Deepest reorg worth expecting, a sensible `max_reorg` for `Reorder`
+    pub max_reorg_hint: u8,
+}
+
+impl ChainParams {
+    pub fn new(magic: u32, genesis_hash: BlockHash) -> Self {
+        Self {
+            magic,
+            genesis_hash,
+            max_reorg_hint: DEFAULT_MAX_REORG_HINT,
+        }
+    }
+
+    pub fn max_reorg_hint(mut self, max_reorg_hint: u8) -> Self {
+        self.max_reorg_hint = max_reorg_hint;
+        self
+    }
+
+    /// Params of a signet with a custom `challenge` script
+    ///
+    /// As in BIP325, the magic is the first 4 bytes of the double sha256 of the
+    /// challenge serialized with its length. All signets share the same genesis block.
+    pub fn signet(challenge: &Script) -> Self {
+        let hash = Sha256dHash::hash(&serialize(challenge));
+        let magic = u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]);
+        Self::new(magic, genesis_block(Network::Signet).block_hash())
+    }
+}
+
+impl From<Network> for ChainParams {
+    fn from(network: Network) -> Self {
+        let max_reorg_hint = match network {
+            // blocks of minimum difficulty after 20 minutes cause deep reorgs
+            Network::Testnet => 30,
+            _ => DEFAULT_MAX_REORG_HINT,
+        };
+        Self::new(network.magic(), genesis_block(network).block_hash())
+            .max_reorg_hint(max_reorg_hint)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ChainParams;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::{Network, Script};
+
+    #[test]
+    fn default_signet() {
+        let challenge = Script::from(Vec::<u8>::from_hex("512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae").unwrap());
+        assert_eq!(
+            ChainParams::signet(&challenge),
+            ChainParams::from(Network::Signet)
+        );
+    }
+}
@@ -1,8 +1,12 @@
+mod chain_locator;
+mod chain_params;
 mod types;
 
 /// Re-export `bitcoin` so donwstream can stay in sync
 pub use bitcoin;
 
+pub use chain_locator::ChainLocator;
+pub use chain_params::ChainParams;
 pub use types::*;
 pub type OwnedBlockData = Box<dyn Iterator<Item = types::BlockData>>;
 
@@ -42,6 +42,12 @@ impl WithPrevBlockHash for bitcoin::Block {
         &self.header.prev_blockhash
     }
 }
+
+impl WithPrevBlockHash for bitcoin::BlockHeader {
+    fn prev_block_hash(&self) -> &BlockHash {
+        &self.prev_blockhash
+    }
+}
 /// Data in a block
 ///
 /// Comes associated with height and hash of the block.
@@ -63,6 +69,7 @@ pub type WithTxId<T> = WithId<Txid, T>;
 
 pub type BlockHeight = u32;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct BlockHeightAndHash {
     pub height: BlockHeight,
     pub hash: BlockHash,
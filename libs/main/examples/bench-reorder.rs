@@ -3,8 +3,9 @@ use std::path::PathBuf;
 use anyhow::Result;
 use block_iter::{
     bench::FallibleIteratorExt as _,
-    source::{read_detect::ReadDetect, reorder::Reorder},
+    source::{decode::ParallelDecode, read_detect::ReadDetect, reorder::Reorder, FsBlock},
 };
+use block_iter_core::WithHeightAndId;
 use clap::Parser;
 use dpc_pariter::IteratorExt as _;
 use fallible_iterator::{FallibleIterator, IteratorExt as _};
@@ -20,16 +21,20 @@ fn main() -> Result<()> {
     let opts: Opts = clap::Parser::parse();
     let network = bitcoin::Network::Bitcoin;
 
-    Reorder::new(
+    // only headers are needed to reorder the blocks; bodies are decoded in parallel after
+    let reorder = Reorder::new(
         network,
         5,
         ReadDetect::new(&opts.bitcoin_core_blocks_dir, network)?
+            .headers_only(true)
             // TODO: add support to `dpc-pariter`
             .iterator()
             .readahead(0)
             .transpose_into_fallible(),
     )
-    .bench_txs()?;
+    .with_output::<WithHeightAndId<FsBlock>>();
+
+    ParallelDecode::default().decode(reorder).bench_txs()?;
 
     Ok(())
 }
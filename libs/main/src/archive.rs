@@ -0,0 +1,368 @@
+//! Archives of [`BlockExtra`] streams, to replay expensive scans
+//!
+//! An archive is made of:
+//! * a header: magic, format version and the [`ChainParams`] of the blocks,
+//! * zstd compressed chunks, each one with the number of blocks and the blocks
+//!   encoded one after the other,
+//! * an index of the chunks by height,
+//! * a trailer with the offset of the index, to find it from the end.
+
+use crate::source::block_extra::BlockExtra;
+use anyhow::{bail, format_err, Result};
+use bitcoin::consensus::{encode, Decodable, Encodable};
+use bitcoin::VarInt;
+use block_iter_core::{BlockHash, BlockHeight, ChainParams};
+use fallible_iterator::FallibleIterator;
+use log::debug;
+use std::collections::VecDeque;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+const MAGIC: [u8; 8] = *b"BIARCHIV";
+const INDEX_MAGIC: [u8; 8] = *b"BIINDEX\0";
+/// Bumped on any change of the layout
+const VERSION: u32 = 1;
+const DEFAULT_CHUNK_BLOCKS: usize = 100;
+const DEFAULT_LEVEL: i32 = 3;
+
+/// Position of a chunk in the archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ChunkEntry {
+    first_height: BlockHeight,
+    count: u32,
+    /// Of the chunk length prefix, since the start of the archive
+    offset: u64,
+}
+
+/// Writes an archive of consecutive [`BlockExtra`]s
+///
+/// The archive is readable only after [`ArchiveWriter::finish`] wrote the index.
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    /// Bytes written so far
+    position: u64,
+    chunk_blocks: usize,
+    level: i32,
+    /// Encoded blocks of the current chunk
+    chunk: Vec<u8>,
+    chunk_count: u32,
+    next_height: Option<BlockHeight>,
+    index: Vec<ChunkEntry>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut writer: W, params: ChainParams) -> Result<Self> {
+        let mut header = vec![];
+        header.extend(MAGIC);
+        VERSION.consensus_encode(&mut header)?;
+        params.magic.consensus_encode(&mut header)?;
+        params.genesis_hash.consensus_encode(&mut header)?;
+        params.max_reorg_hint.consensus_encode(&mut header)?;
+        writer.write_all(&header)?;
+
+        Ok(Self {
+            writer,
+            position: header.len() as u64,
+            chunk_blocks: DEFAULT_CHUNK_BLOCKS,
+            level: DEFAULT_LEVEL,
+            chunk: vec![],
+            chunk_count: 0,
+            next_height: None,
+            index: vec![],
+        })
+    }
+
+    /// Number of blocks compressed together
+    ///
+    /// Bigger chunks compress better, but reading from a given height has to
+    /// decompress more blocks before it.
+    pub fn chunk_blocks(mut self, chunk_blocks: usize) -> Self {
+        self.chunk_blocks = chunk_blocks.max(1);
+        self
+    }
+
+    /// zstd compression level
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Append the block following the last one
+    pub fn push(&mut self, block: &BlockExtra) -> Result<()> {
+        if let Some(next_height) = self.next_height {
+            if block.height != next_height {
+                bail!(
+                    "Block {} at height {} doesn't follow the last one, expected height {}",
+                    block.block_hash,
+                    block.height,
+                    next_height
+                );
+            }
+        }
+        if self.chunk_count == 0 {
+            self.index.push(ChunkEntry {
+                first_height: block.height,
+                count: 0,
+                offset: self.position,
+            });
+        }
+        block.consensus_encode(&mut self.chunk)?;
+        self.chunk_count += 1;
+        self.next_height = Some(block.height + 1);
+        if self.chunk_count as usize >= self.chunk_blocks {
+            self.write_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self) -> Result<()> {
+        if self.chunk_count == 0 {
+            return Ok(());
+        }
+        let mut plain = vec![];
+        self.chunk_count.consensus_encode(&mut plain)?;
+        plain.append(&mut self.chunk);
+        let compressed = zstd::stream::encode_all(&plain[..], self.level)?;
+
+        self.writer
+            .write_all(&(compressed.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&compressed)?;
+        self.position += 4 + compressed.len() as u64;
+
+        let entry = self.index.last_mut().expect("added with the first block");
+        entry.count = self.chunk_count;
+        debug!(
+            "Archived blocks {}..{} in {} bytes",
+            entry.first_height,
+            entry.first_height + entry.count,
+            compressed.len()
+        );
+        self.chunk_count = 0;
+        Ok(())
+    }
+
+    /// Write the last chunk and the index
+    pub fn finish(mut self) -> Result<W> {
+        self.write_chunk()?;
+        let index_offset = self.position;
+        let mut index = vec![];
+        VarInt(self.index.len() as u64).consensus_encode(&mut index)?;
+        for entry in self.index.iter() {
+            entry.first_height.consensus_encode(&mut index)?;
+            entry.count.consensus_encode(&mut index)?;
+            entry.offset.consensus_encode(&mut index)?;
+        }
+        index_offset.consensus_encode(&mut index)?;
+        index.extend(INDEX_MAGIC);
+        self.writer.write_all(&index)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Archive all the blocks of `iter`, e.g. a `Reorder`, and finish
+    pub fn sink<I>(mut self, mut iter: I) -> Result<W>
+    where
+        I: FallibleIterator<Item = BlockExtra, Error = anyhow::Error>,
+    {
+        while let Some(block) = iter.next()? {
+            self.push(&block)?;
+        }
+        self.finish()
+    }
+}
+
+/// Reads back the blocks of an archive written by [`ArchiveWriter`], in order
+pub struct ArchiveReader<R> {
+    reader: R,
+    params: ChainParams,
+    index: Vec<ChunkEntry>,
+    /// Next chunk to read
+    next_chunk: usize,
+    /// Blocks below are skipped
+    start_height: BlockHeight,
+    blocks: VecDeque<BlockExtra>,
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            bail!("Not a block archive");
+        }
+        let version = u32::consensus_decode(&mut reader)?;
+        if version != VERSION {
+            bail!("Unsupported archive version {}", version);
+        }
+        let params = ChainParams::new(
+            u32::consensus_decode(&mut reader)?,
+            BlockHash::consensus_decode(&mut reader)?,
+        )
+        .max_reorg_hint(u8::consensus_decode(&mut reader)?);
+
+        reader.seek(SeekFrom::End(-16))?;
+        let index_offset = u64::consensus_decode(&mut reader)?;
+        let mut index_magic = [0u8; 8];
+        reader.read_exact(&mut index_magic)?;
+        if index_magic != INDEX_MAGIC {
+            bail!("Archive has no index, was it finished?");
+        }
+        reader.seek(SeekFrom::Start(index_offset))?;
+        let len = VarInt::consensus_decode(&mut reader)?.0;
+        let mut index = Vec::with_capacity(len.min(1 << 16) as usize);
+        for _ in 0..len {
+            index.push(ChunkEntry {
+                first_height: u32::consensus_decode(&mut reader)?,
+                count: u32::consensus_decode(&mut reader)?,
+                offset: u64::consensus_decode(&mut reader)?,
+            });
+        }
+
+        let start_height = index.first().map_or(0, |entry| entry.first_height);
+        Ok(Self {
+            reader,
+            params,
+            index,
+            next_chunk: 0,
+            start_height,
+            blocks: VecDeque::new(),
+        })
+    }
+
+    pub fn params(&self) -> ChainParams {
+        self.params
+    }
+
+    /// Heights of the archived blocks
+    pub fn heights(&self) -> std::ops::Range<BlockHeight> {
+        match (self.index.first(), self.index.last()) {
+            (Some(first), Some(last)) => first.first_height..last.first_height + last.count,
+            _ => 0..0,
+        }
+    }
+
+    /// Start reading at `height`, decompressing only the chunk containing it
+    pub fn from_height(mut self, height: BlockHeight) -> Result<Self> {
+        if !self.heights().contains(&height) {
+            bail!(
+                "Height {} not in the archive, which has {:?}",
+                height,
+                self.heights()
+            );
+        }
+        self.next_chunk = self
+            .index
+            .iter()
+            .rposition(|entry| entry.first_height <= height)
+            .expect("height in the archive");
+        self.start_height = height;
+        self.blocks.clear();
+        Ok(self)
+    }
+
+    fn read_chunk(&mut self, entry: ChunkEntry) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        let mut len = [0u8; 4];
+        self.reader.read_exact(&mut len)?;
+        let mut compressed = vec![0u8; u32::from_le_bytes(len) as usize];
+        self.reader.read_exact(&mut compressed)?;
+        let plain = zstd::stream::decode_all(&compressed[..])?;
+
+        let mut cursor = Cursor::new(plain);
+        let count = u32::consensus_decode(&mut cursor)?;
+        if count != entry.count {
+            bail!(
+                "Chunk at {} has {} blocks, {} in the index",
+                entry.offset,
+                count,
+                entry.count
+            );
+        }
+        for _ in 0..count {
+            let block = BlockExtra::consensus_decode(&mut cursor).map_err(|e: encode::Error| {
+                format_err!("Corrupted chunk at {}: {}", entry.offset, e)
+            })?;
+            if block.height >= self.start_height {
+                self.blocks.push_back(block);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> FallibleIterator for ArchiveReader<R> {
+    type Item = BlockExtra;
+    type Error = anyhow::Error;
+
+    fn next(&mut self) -> Result<Option<Self::Item>> {
+        while self.blocks.is_empty() {
+            let entry = match self.index.get(self.next_chunk) {
+                Some(entry) => *entry,
+                None => return Ok(None),
+            };
+            self.next_chunk += 1;
+            self.read_chunk(entry)?;
+        }
+        Ok(self.blocks.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ArchiveReader, ArchiveWriter};
+    use crate::source::block_extra::BlockExtra;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::{Network, OutPoint, TxOut};
+    use fallible_iterator::FallibleIterator;
+    use std::io::Cursor;
+
+    fn block_extra(height: u32) -> BlockExtra {
+        let block = genesis_block(Network::Regtest);
+        BlockExtra {
+            block_hash: block.block_hash(),
+            size: 285,
+            block,
+            next: vec![],
+            height,
+            outpoint_values: vec![(
+                OutPoint::default(),
+                TxOut {
+                    value: height as u64,
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = ArchiveWriter::new(Cursor::new(vec![]), Network::Regtest.into())
+            .unwrap()
+            .chunk_blocks(100);
+        for height in 10..260 {
+            writer.push(&block_extra(height)).unwrap();
+        }
+        assert!(writer.push(&block_extra(300)).is_err());
+        let archive = writer.finish().unwrap().into_inner();
+
+        let reader = ArchiveReader::new(Cursor::new(&archive)).unwrap();
+        assert_eq!(reader.params(), Network::Regtest.into());
+        assert_eq!(reader.heights(), 10..260);
+        let blocks: Vec<_> = reader.collect().unwrap();
+        assert_eq!(blocks.len(), 250);
+        for (block, height) in blocks.iter().zip(10..) {
+            assert_eq!(block, &block_extra(height));
+        }
+
+        let reader = ArchiveReader::new(Cursor::new(&archive))
+            .unwrap()
+            .from_height(150)
+            .unwrap();
+        let heights: Vec<_> = reader.map(|block| Ok(block.height)).collect().unwrap();
+        assert_eq!(heights, (150..260).collect::<Vec<_>>());
+
+        assert!(ArchiveReader::new(Cursor::new(&archive[..archive.len() - 1])).is_err());
+    }
+}
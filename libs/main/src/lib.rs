@@ -1,3 +1,10 @@
 pub use block_iter_rpc as rpc;
+#[cfg(feature = "archive")]
+pub mod archive;
 pub mod bench;
 pub mod source;
+pub mod store;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "txindex")]
+pub mod txindex;
@@ -1,13 +1,78 @@
-use block_iter_core::BlockHash;
+use anyhow::{format_err, Result};
+use block_iter_core::{
+    bitcoin::{consensus::Decodable, BlockHeader},
+    BlockHash, BlockHeight, WithHeightAndId,
+};
 use std::{
     fs::File,
+    io::{Read, Seek, SeekFrom},
+    ops::DerefMut,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
 pub mod block_extra;
+pub mod decode;
+pub mod format;
 pub mod read_detect;
 pub mod reorder;
 
+/// A blk file, opened once and shared by the blocks stored in it
+#[derive(Debug)]
+pub struct BlkFile {
+    path: PathBuf,
+    /// It's a Mutex to allow to be sent between threads but only one thread (reorder) mutably
+    /// access to it so there is no contention. (Arc alone isn't enough cause it can't be mutated,
+    /// RefCell can be mutated but not sent between threads)
+    file: Mutex<File>,
+}
+
+impl BlkFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(File::open(path)?),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read `len` bytes at `pos`, locking the file only for the time of reading
+    pub fn read_at(&self, pos: u64, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        let mut guard = self
+            .file
+            .lock()
+            .map_err(|_e| format_err!("locking failed"))?;
+        let file = guard.deref_mut();
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Read `len` bytes from `offset` of the block `hash`, stored at `start..end`
+    pub(crate) fn read_block_range(
+        &self,
+        hash: &BlockHash,
+        (start, end): (usize, usize),
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        if start + offset + len > end {
+            return Err(format_err!(
+                "Range {}+{} out of block {} of {} bytes",
+                offset,
+                len,
+                hash,
+                end - start
+            ));
+        }
+        self.read_at((start + offset) as u64, len)
+    }
+}
+
 /// Before reorder we keep only the position of the block in the file system and data relative
 /// to the block hash, the previous hash and the following hash (populated during reorder phase)
 /// We will need to read the block from disk again, but by doing so we will avoid using too much
@@ -16,10 +81,7 @@ pub mod reorder;
 pub struct FsBlock {
     /// the file the block identified by `hash` is stored in. Multiple blocks are stored in the
     /// and we don't want to open/close the file many times for performance reasons so it's shared.
-    /// It's a Mutex to allow to be sent between threads but only one thread (reorder) mutably
-    /// access to it so there is no contention. (Arc alone isn't enough cause it can't be mutated,
-    /// RefCell can be mutated but not sent between threads)
-    pub file: Arc<Mutex<File>>,
+    pub file: Arc<BlkFile>,
 
     /// The start position in bytes in the `file` at which the block identified by `hash`
     pub start: usize,
@@ -33,7 +95,55 @@ pub struct FsBlock {
     /// The hash of the block previous to this one, `block.header.prev_blockhash`
     pub prev: BlockHash,
 
+    /// The difficulty target of this block, `block.header.bits`, used to compare the work of forks
+    pub bits: u32,
+
     /// The hash of the blocks following this one. It is populated during the reorder phase, it can
     /// be more than one because of reorgs.
     pub next: Vec<BlockHash>,
 }
+
+impl FsBlock {
+    /// Read the raw bytes of the block
+    ///
+    /// The file is locked only for the time of reading.
+    pub fn read_bytes(&self) -> Result<Vec<u8>> {
+        self.read_range(0, self.end - self.start)
+    }
+
+    /// Read `len` bytes of the block, from `offset` since its start
+    pub fn read_range(&self, offset: usize, len: usize) -> Result<Vec<u8>> {
+        self.file
+            .read_block_range(&self.hash, (self.start, self.end), offset, len)
+    }
+}
+
+/// Conversion of an [`FsBlock`] in its final position into an item yielded by
+/// [`reorder::Reorder`]
+pub trait FromFsBlock: Sized {
+    fn from_fs_block(fs_block: FsBlock, height: BlockHeight) -> Result<Self>;
+}
+
+/// Reads only the header, skipping the rest of the block
+impl FromFsBlock for WithHeightAndId<BlockHeader> {
+    fn from_fs_block(fs_block: FsBlock, height: BlockHeight) -> Result<Self> {
+        let bytes = fs_block.read_range(0, 80)?;
+        Ok(WithHeightAndId {
+            height,
+            id: fs_block.hash,
+            data: BlockHeader::consensus_decode(&bytes[..])?,
+        })
+    }
+}
+
+/// Doesn't read anything; use [`decode::ParallelDecode`] to read and decode
+/// the blocks in parallel later
+impl FromFsBlock for WithHeightAndId<FsBlock> {
+    fn from_fs_block(fs_block: FsBlock, height: BlockHeight) -> Result<Self> {
+        Ok(WithHeightAndId {
+            height,
+            id: fs_block.hash,
+            data: fs_block,
+        })
+    }
+}
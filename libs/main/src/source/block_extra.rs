@@ -1,13 +1,11 @@
-use super::FsBlock;
-use anyhow::format_err;
-use block_iter_core::bitcoin::consensus::{Decodable, Encodable};
-use block_iter_core::bitcoin::{Block, BlockHash, OutPoint, Transaction, TxOut};
-use block_iter_core::WithTransactions;
-use log::debug;
+use super::{FromFsBlock, FsBlock};
+use block_iter_core::bitcoin::consensus::{encode, Decodable, Encodable};
+use block_iter_core::bitcoin::{Block, BlockHash, OutPoint, Transaction, TxOut, VarInt};
+use block_iter_core::{BlockHeight, WithHeightAndId, WithTransactions};
+use block_iter_rpc::BlockWithPrevouts;
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
-use std::ops::DerefMut;
+use std::io::{Cursor, Read, Write};
 
 /// The bitcoin block and additional metadata returned by the [iterate] method
 #[derive(Debug, Eq, PartialEq)]
@@ -39,16 +37,10 @@ impl TryFrom<FsBlock> for BlockExtra {
     type Error = anyhow::Error;
 
     fn try_from(fs_block: FsBlock) -> Result<Self, Self::Error> {
-        let mut guard = fs_block
-            .file
-            .lock()
-            .map_err(|_e| format_err!("locking failed"))?;
-        let file = guard.deref_mut();
-        file.seek(SeekFrom::Start(fs_block.start as u64))?;
-        debug!("going to read: {:?}", file);
-        let reader = BufReader::new(file);
+        // decode without holding the lock, so other threads can read the same file
+        let bytes = fs_block.read_bytes()?;
         Ok(BlockExtra {
-            block: Block::consensus_decode(reader)?,
+            block: Block::consensus_decode(&bytes[..])?,
             block_hash: fs_block.hash,
             size: (fs_block.end - fs_block.start) as u32,
             next: fs_block.next,
@@ -58,6 +50,27 @@ impl TryFrom<FsBlock> for BlockExtra {
     }
 }
 
+impl FromFsBlock for BlockExtra {
+    fn from_fs_block(fs_block: FsBlock, height: BlockHeight) -> anyhow::Result<Self> {
+        let mut block_extra = BlockExtra::try_from(fs_block)?;
+        block_extra.height = height;
+        Ok(block_extra)
+    }
+}
+
+impl From<WithHeightAndId<BlockWithPrevouts>> for BlockExtra {
+    fn from(block: WithHeightAndId<BlockWithPrevouts>) -> Self {
+        BlockExtra {
+            size: block.data.block.get_size() as u32,
+            block: block.data.block,
+            block_hash: block.id,
+            next: vec![],
+            height: block.height,
+            outpoint_values: block.data.outpoint_values,
+        }
+    }
+}
+
 impl BlockExtra {
     /// Returns the average transaction fee in the block
     pub fn average_fee(&self) -> Option<f64> {
@@ -84,36 +97,65 @@ impl BlockExtra {
     }
 }
 
+/// Starts the versioned encoding; the legacy one starts with the version of the block,
+/// which is never -1
+const ENCODING_MARKER: [u8; 4] = [0xff; 4];
+/// Bumped only on breaking changes, new fields go in the optional ones
+const ENCODING_VERSION: u8 = 1;
+
+/// Versioned encoding
+///
+/// After the marker and the version come the fields, with `outpoint_values`
+/// sorted by outpoint, so the encoding is deterministic. Then optional fields,
+/// each one with its type and length, so decoders skip the ones they don't know.
+/// None are defined yet.
 impl Encodable for BlockExtra {
     fn consensus_encode<W: Write>(&self, mut writer: W) -> Result<usize, std::io::Error> {
         let mut written = 0;
+        writer.write_all(&ENCODING_MARKER)?;
+        written += ENCODING_MARKER.len();
+        written += ENCODING_VERSION.consensus_encode(&mut writer)?;
         written += self.block.consensus_encode(&mut writer)?;
         written += self.block_hash.consensus_encode(&mut writer)?;
         written += self.size.consensus_encode(&mut writer)?;
         written += self.next.consensus_encode(&mut writer)?;
         written += self.height.consensus_encode(&mut writer)?;
-        written += (self.outpoint_values.len() as u32).consensus_encode(&mut writer)?;
-        for (out_point, tx_out) in self.outpoint_values.iter() {
+        let mut outpoint_values: Vec<_> = self.outpoint_values.iter().collect();
+        outpoint_values.sort_unstable_by_key(|(out_point, _)| *out_point);
+        written += VarInt(outpoint_values.len() as u64).consensus_encode(&mut writer)?;
+        for (out_point, tx_out) in outpoint_values {
             written += out_point.consensus_encode(&mut writer)?;
             written += tx_out.consensus_encode(&mut writer)?;
         }
+        // number of optional fields
+        written += VarInt(0).consensus_encode(&mut writer)?;
         Ok(written)
     }
 }
 
+/// Decodes both the versioned and the legacy encoding
 impl Decodable for BlockExtra {
-    fn consensus_decode<D: Read>(
-        mut d: D,
-    ) -> Result<Self, block_iter_core::bitcoin::consensus::encode::Error> {
-        Ok(BlockExtra {
+    fn consensus_decode<D: Read>(mut d: D) -> Result<Self, encode::Error> {
+        let mut marker = [0u8; 4];
+        d.read_exact(&mut marker)?;
+        if marker != ENCODING_MARKER {
+            return decode_legacy(Cursor::new(marker).chain(d));
+        }
+        let version = u8::consensus_decode(&mut d)?;
+        if version != ENCODING_VERSION {
+            return Err(encode::Error::ParseFailed(
+                "unsupported BlockExtra encoding version",
+            ));
+        }
+        let block_extra = BlockExtra {
             block: Decodable::consensus_decode(&mut d)?,
             block_hash: Decodable::consensus_decode(&mut d)?,
             size: Decodable::consensus_decode(&mut d)?,
             next: Decodable::consensus_decode(&mut d)?,
             height: Decodable::consensus_decode(&mut d)?,
             outpoint_values: {
-                let len = u32::consensus_decode(&mut d)?;
-                let mut m = HashMap::with_capacity(len as usize);
+                let len = VarInt::consensus_decode(&mut d)?.0;
+                let mut m = HashMap::with_capacity(len.min(1 << 16) as usize);
                 for _ in 0..len {
                     m.insert(
                         Decodable::consensus_decode(&mut d)?,
@@ -122,16 +164,49 @@ impl Decodable for BlockExtra {
                 }
                 m
             },
-        })
+        };
+        let optional_fields = VarInt::consensus_decode(&mut d)?.0;
+        for _ in 0..optional_fields {
+            let _field_type = VarInt::consensus_decode(&mut d)?;
+            let _value = Vec::<u8>::consensus_decode(&mut d)?;
+        }
+        Ok(block_extra)
     }
 }
 
+/// Fields in a fixed order, `outpoint_values` in the `HashMap` order
+fn decode_legacy<D: Read>(mut d: D) -> Result<BlockExtra, encode::Error> {
+    Ok(BlockExtra {
+        block: Decodable::consensus_decode(&mut d)?,
+        block_hash: Decodable::consensus_decode(&mut d)?,
+        size: Decodable::consensus_decode(&mut d)?,
+        next: Decodable::consensus_decode(&mut d)?,
+        height: Decodable::consensus_decode(&mut d)?,
+        outpoint_values: {
+            let len = u32::consensus_decode(&mut d)?;
+            let mut m = HashMap::with_capacity(len as usize);
+            for _ in 0..len {
+                m.insert(
+                    Decodable::consensus_decode(&mut d)?,
+                    Decodable::consensus_decode(&mut d)?,
+                );
+            }
+            m
+        },
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::BlockExtra;
+    use block_iter_core::bitcoin::blockdata::constants::genesis_block;
     use block_iter_core::bitcoin::consensus::deserialize;
     use block_iter_core::bitcoin::consensus::serialize;
-    use block_iter_core::bitcoin::{Block, BlockHeader, OutPoint, TxOut};
+    use block_iter_core::bitcoin::hashes::hex::FromHex;
+    use block_iter_core::bitcoin::hashes::Hash;
+    use block_iter_core::bitcoin::{
+        Block, BlockHash, BlockHeader, Network, OutPoint, Script, TxOut, Txid,
+    };
     use std::collections::HashMap;
 
     #[test]
@@ -162,4 +237,46 @@ mod test {
         let deser = deserialize(&ser).unwrap();
         assert_eq!(be, deser);
     }
+
+    fn golden() -> BlockExtra {
+        let block = genesis_block(Network::Regtest);
+        BlockExtra {
+            block_hash: block.block_hash(),
+            size: 285,
+            block,
+            next: vec![BlockHash::from_inner([0x33; 32])],
+            height: 7,
+            outpoint_values: vec![
+                (
+                    OutPoint::new(Txid::from_inner([0x22; 32]), 0),
+                    TxOut {
+                        value: 10000,
+                        script_pubkey: Script::new(),
+                    },
+                ),
+                (
+                    OutPoint::new(Txid::from_inner([0x11; 32]), 1),
+                    TxOut {
+                        value: 5000,
+                        script_pubkey: Script::from(vec![0x51]),
+                    },
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn block_extra_golden() {
+        let v1 =
+            Vec::<u8>::from_hex(include_str!("../../test-data/block_extra_v1.hex").trim()).unwrap();
+        assert_eq!(serialize(&golden()), v1);
+        assert_eq!(deserialize::<BlockExtra>(&v1).unwrap(), golden());
+
+        let legacy =
+            Vec::<u8>::from_hex(include_str!("../../test-data/block_extra_legacy.hex").trim())
+                .unwrap();
+        assert_eq!(deserialize::<BlockExtra>(&legacy).unwrap(), golden());
+    }
 }
@@ -0,0 +1,68 @@
+//! Order-preserving parallel decoding of blocks, after [`super::reorder::Reorder`]
+
+use super::{block_extra::BlockExtra, FromFsBlock, FsBlock};
+use anyhow::Result;
+use block_iter_core::WithHeightAndId;
+use dpc_pariter::IteratorExt as _;
+use fallible_iterator::{FallibleIterator, IteratorExt as _};
+
+/// Reads and decodes ordered `FsBlock`s into [`BlockExtra`]s using multiple threads
+///
+/// Use with `Reorder::with_output::<WithHeightAndId<FsBlock>>()`, which only
+/// reorders the blocks by hashes, leaving the expensive part to this stage.
+/// The order of the blocks is preserved.
+#[derive(Debug, Clone, Copy)]
+pub struct ParallelDecode {
+    threads: usize,
+    read_ahead: usize,
+}
+
+impl Default for ParallelDecode {
+    fn default() -> Self {
+        let threads = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4);
+        Self {
+            threads,
+            read_ahead: threads * 4,
+        }
+    }
+}
+
+impl ParallelDecode {
+    /// Set the number of decoding threads
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Set how many blocks can be decoded ahead of the one to be yielded next
+    pub fn read_ahead(mut self, read_ahead: usize) -> Self {
+        self.read_ahead = read_ahead;
+        self
+    }
+
+    pub fn decode<I>(
+        self,
+        iter: I,
+    ) -> impl FallibleIterator<Item = BlockExtra, Error = anyhow::Error>
+    where
+        I: FallibleIterator<Item = WithHeightAndId<FsBlock>, Error = anyhow::Error>
+            + Send
+            + 'static,
+    {
+        let ParallelDecode {
+            threads,
+            read_ahead,
+        } = self;
+        iter.iterator()
+            .parallel_map_custom(
+                |o| o.threads(threads).buffer_size(read_ahead),
+                |item: Result<WithHeightAndId<FsBlock>>| {
+                    let item = item?;
+                    BlockExtra::from_fs_block(item.data, item.height)
+                },
+            )
+            .transpose_into_fallible()
+    }
+}
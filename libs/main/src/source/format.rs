@@ -0,0 +1,72 @@
+use anyhow::Result;
+use bitcoin::consensus::{encode, Decodable};
+use bitcoin::BlockHash;
+use std::io::Read;
+
+#[cfg(feature = "elements")]
+mod elements;
+#[cfg(feature = "elements")]
+pub use self::elements::Elements;
+
+/// What `ReadDetect` and `Reorder` need to know about a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderInfo {
+    pub hash: BlockHash,
+    pub prev: BlockHash,
+    /// Difficulty target, used to compare the work of forks
+    pub bits: u32,
+}
+
+/// Encoding of the blocks stored in blk files
+///
+/// The blk files of chains derived from Bitcoin Core share the same record layout:
+/// magic, size and the encoded block. Only the block encoding differs.
+///
+/// I/O errors must be returned as `std::io::Error`, so they can be told apart from
+/// invalid blocks, see [`encode_error`].
+pub trait BlockFormat: Send + 'static {
+    type Block;
+
+    /// Decode the header at the start of an encoded block
+    fn decode_header<R: Read>(reader: R) -> Result<HeaderInfo>;
+
+    fn decode_block<R: Read>(reader: R) -> Result<Self::Block>;
+
+    fn header_info(block: &Self::Block) -> HeaderInfo;
+}
+
+/// Blocks of Bitcoin and chains using the same encoding
+pub struct Bitcoin;
+
+impl BlockFormat for Bitcoin {
+    type Block = bitcoin::Block;
+
+    fn decode_header<R: Read>(reader: R) -> Result<HeaderInfo> {
+        let header = bitcoin::BlockHeader::consensus_decode(reader).map_err(encode_error)?;
+        Ok(HeaderInfo {
+            hash: header.block_hash(),
+            prev: header.prev_blockhash,
+            bits: header.bits,
+        })
+    }
+
+    fn decode_block<R: Read>(reader: R) -> Result<Self::Block> {
+        bitcoin::Block::consensus_decode(reader).map_err(encode_error)
+    }
+
+    fn header_info(block: &Self::Block) -> HeaderInfo {
+        HeaderInfo {
+            hash: block.block_hash(),
+            prev: block.header.prev_blockhash,
+            bits: block.header.bits,
+        }
+    }
+}
+
+/// Keep I/O errors as `std::io::Error`
+pub fn encode_error(e: encode::Error) -> anyhow::Error {
+    match e {
+        encode::Error::Io(e) => e.into(),
+        e => e.into(),
+    }
+}
@@ -0,0 +1,60 @@
+use super::{BlockFormat, HeaderInfo};
+use crate::source::{FromFsBlock, FsBlock};
+use anyhow::Result;
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+use block_iter_core::{BlockHeight, WithHeightAndId};
+use elements::encode::Decodable;
+use std::io::Read;
+
+/// Blocks are signed, each one has the same work
+const BITS: u32 = 0x207fffff;
+
+/// Blocks of Elements based chains, like Liquid
+pub struct Elements;
+
+impl Elements {
+    fn info(header: &elements::BlockHeader) -> HeaderInfo {
+        HeaderInfo {
+            hash: BlockHash::from_inner(header.block_hash().into_inner()),
+            prev: BlockHash::from_inner(header.prev_blockhash.into_inner()),
+            bits: BITS,
+        }
+    }
+}
+
+fn encode_error(e: elements::encode::Error) -> anyhow::Error {
+    match e {
+        elements::encode::Error::Io(e) => e.into(),
+        e => e.into(),
+    }
+}
+
+impl BlockFormat for Elements {
+    type Block = elements::Block;
+
+    fn decode_header<R: Read>(reader: R) -> Result<HeaderInfo> {
+        let header = elements::BlockHeader::consensus_decode(reader).map_err(encode_error)?;
+        Ok(Self::info(&header))
+    }
+
+    fn decode_block<R: Read>(reader: R) -> Result<Self::Block> {
+        elements::Block::consensus_decode(reader).map_err(encode_error)
+    }
+
+    fn header_info(block: &Self::Block) -> HeaderInfo {
+        Self::info(&block.header)
+    }
+}
+
+/// The equivalent of `BlockExtra` for Elements, use with `Reorder::with_output`
+impl FromFsBlock for WithHeightAndId<elements::Block> {
+    fn from_fs_block(fs_block: FsBlock, height: BlockHeight) -> Result<Self> {
+        let bytes = fs_block.read_bytes()?;
+        Ok(WithHeightAndId {
+            height,
+            id: fs_block.hash,
+            data: Elements::decode_block(&bytes[..])?,
+        })
+    }
+}
@@ -1,16 +1,23 @@
-use super::FsBlock;
-use anyhow::{format_err, Result};
-use block_iter_core::bitcoin::consensus::Decodable;
-use block_iter_core::bitcoin::{Block, BlockHash, Network};
+use super::format::{Bitcoin, BlockFormat};
+use super::{BlkFile, FsBlock};
+use anyhow::{bail, format_err, Result};
+use block_iter_core::bitcoin::consensus::{encode, Decodable};
+use block_iter_core::bitcoin::{BlockHash, Network};
+use block_iter_core::ChainParams;
+use block_iter_rpc::ShutdownHandle;
+use cache::ScanCache;
 use fallible_iterator::FallibleIterator;
-use fallible_iterator::{ IteratorExt};
-use itertools::Itertools;
-use log::{error, info};
-use std::collections::HashSet;
+use log::{debug, info, warn};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, Read, Seek};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+mod cache;
 
 /// Save half memory in comparison to using directly HashSet<BlockHash> while providing enough
 /// bytes to reasonably prevent collisions. Use the non-zero part of the hash
@@ -25,23 +32,74 @@ impl Seen {
     }
 }
 
+#[derive(Clone)]
 pub struct DetectedBlock {
     start: usize,
     end: usize,
     hash: BlockHash,
     prev: BlockHash,
+    bits: u32,
+}
+
+/// Finds the blocks in the blk files of a blocks dir, in the order they are stored
+///
+/// `F` is the encoding of the blocks, [`Bitcoin`] by default, use
+/// [`ReadDetect::with_format`] for other ones.
+pub struct ReadDetect<F = Bitcoin> {
+    blocks_dir: PathBuf,
+    /// Files left to scan
+    paths: VecDeque<PathBuf>,
+    params: ChainParams,
+    headers_only: bool,
+    /// Skip the files before the one containing this block
+    start_block: Option<BlockHash>,
+    strict: bool,
+    /// Sidecar file caching the blocks found in each file
+    cache_path: Option<PathBuf>,
+    /// Keep polling the blocks dir, see [`ReadDetect::follow`]
+    follow: Option<Duration>,
+    /// Stops following
+    shutdown: ShutdownHandle,
+    /// One entry for every file scanned so far
+    reports: Arc<Mutex<Vec<FileScanReport>>>,
+    /// Started lazily, on the first `next`
+    started: bool,
+    cache: Option<ScanCache>,
+    seen: Seen,
+    /// Blocks found in the last scan, not returned yet
+    pending: VecDeque<FsBlock>,
+    /// The file scanned last, resumed when following
+    last: Option<LastFile>,
+    _format: PhantomData<fn() -> F>,
+}
+
+struct LastFile {
+    file: Arc<BlkFile>,
+    valid_end: u64,
+    /// Size and modification time of the file right before the last scan
+    len: u64,
+    modified: Option<SystemTime>,
 }
 
-pub struct ReadDetect {
-    iter: Box<dyn FallibleIterator<Item = FsBlock, Error = anyhow::Error> + Send>,
+impl LastFile {
+    /// Whether the file was written since the last scan
+    ///
+    /// Saves scanning the zeros in the pre-allocated space of the file
+    /// on every poll, up to 16 MiB of them.
+    fn changed(&self) -> Result<bool> {
+        let metadata = std::fs::metadata(self.file.path())?;
+        Ok(metadata.len() != self.len || metadata.modified().ok() != self.modified)
+    }
 }
+
 impl DetectedBlock {
-    fn into_fs_block(self, file: &Arc<Mutex<File>>) -> FsBlock {
+    fn into_fs_block(self, file: &Arc<BlkFile>) -> FsBlock {
         FsBlock {
             start: self.start,
             end: self.end,
             hash: self.hash,
             prev: self.prev,
+            bits: self.bits,
             file: Arc::clone(file),
             next: vec![],
         }
@@ -49,61 +107,541 @@ impl DetectedBlock {
 }
 
 impl ReadDetect {
-    pub fn new(blocks_dir: &Path, network: Network) -> Result<Self> {
-        let block_files_glob = blocks_dir.join("blk*.dat");
-        info!("listing block files at {:?}", &block_files_glob);
-        let mut paths: Vec<PathBuf> = glob::glob(
-            block_files_glob
-                .to_str()
-                .ok_or_else(|| format_err!("Glob incorrect"))?,
-        )?
-        .collect::<std::result::Result<Vec<_>, _>>()
-        .map_err(|e| format_err!("Path error: {}", e))?;
-        paths.sort();
+    /// Read the blk files in `blocks_dir` of the chain with `params`, e.g. a `bitcoin::Network`
+    ///
+    /// Fails if the first block file is of another chain.
+    pub fn new(blocks_dir: &Path, params: impl Into<ChainParams>) -> Result<Self> {
+        let params = params.into();
+        let paths = list_block_files(blocks_dir)?;
         info!("There are {} block files", paths.len());
-        let mut seen = Seen::new();
+        check_magic(&paths, &params)?;
 
-        let iter = paths
-            .into_iter()
-            .map(move |path| {
-                let file = File::open(&path)?;
-                let mut reader = BufReader::new(file);
-                let detected_blocks = detect(&mut reader, network.magic())?;
-                drop(reader);
+        Ok(Self {
+            blocks_dir: blocks_dir.to_path_buf(),
+            paths: paths.into(),
+            params,
+            headers_only: false,
+            start_block: None,
+            strict: false,
+            cache_path: None,
+            follow: None,
+            shutdown: ShutdownHandle::new(),
+            reports: Default::default(),
+            started: false,
+            cache: None,
+            seen: Seen::new(),
+            pending: VecDeque::new(),
+            last: None,
+            _format: PhantomData,
+        })
+    }
 
-                let file = File::open(&path)?;
-                let file = Arc::new(Mutex::new(file));
+    /// Read the blk files in `blocks_dir`, of the network detected by [`detect_network`]
+    pub fn from_dir(blocks_dir: &Path) -> Result<Self> {
+        Self::new(blocks_dir, detect_network(blocks_dir)?)
+    }
 
-                let fs_blocks: Vec<_> = detected_blocks
-                    .into_iter()
-                    .filter(|e| seen.insert(&e.hash))
-                    .map(|e| e.into_fs_block(&file))
-                    .collect();
+    /// Read blocks with another encoding, e.g. `Elements`
+    pub fn with_format<F: BlockFormat>(self) -> ReadDetect<F> {
+        ReadDetect {
+            blocks_dir: self.blocks_dir,
+            paths: self.paths,
+            params: self.params,
+            headers_only: self.headers_only,
+            start_block: self.start_block,
+            strict: self.strict,
+            cache_path: self.cache_path,
+            follow: self.follow,
+            shutdown: self.shutdown,
+            reports: self.reports,
+            started: self.started,
+            cache: self.cache,
+            seen: self.seen,
+            pending: self.pending,
+            last: self.last,
+            _format: PhantomData,
+        }
+    }
+}
 
-                // TODO if 0 blocks found, maybe wrong directory
+impl<F: BlockFormat> ReadDetect<F> {
+    /// Params of the chain being read, e.g. to build a `Reorder` after [`ReadDetect::from_dir`]
+    pub fn chain_params(&self) -> ChainParams {
+        self.params
+    }
 
-                Ok(fs_blocks)
-            })
-            .flatten_ok()
-            .transpose_into_fallible();
+    /// Don't decode whole blocks when scanning the block files
+    ///
+    /// Blocks are skipped over using the size from the record preamble,
+    /// and only headers are decoded, which is much faster, but doesn't check
+    /// if the blocks are well-formed. Use when later stages don't need block
+    /// bodies either, e.g. `Reorder::with_output::<WithHeightAndId<BlockHeader>>`.
+    pub fn headers_only(mut self, enabled: bool) -> Self {
+        self.headers_only = enabled;
+        self
+    }
 
-        Ok(Self {
-            iter: Box::new(iter),
-        })
+    /// Skip the block files that come before the one containing `hash`
+    ///
+    /// The files are searched from the last one, decoding only headers. The file
+    /// right before the one containing the block is still read, as blocks in
+    /// neighbouring files can be out of order. Use along with `Reorder::start_after`
+    /// to resume processing without reading the whole chain again.
+    pub fn start_from_block(mut self, hash: BlockHash) -> Self {
+        self.start_block = Some(hash);
+        self
+    }
+
+    /// Fail on any issue found while scanning the block files
+    ///
+    /// By default skipped bytes, corrupted records and truncated records are logged
+    /// and the scan carries on with the next record, see [`ReadDetect::report`].
+    pub fn strict(mut self, enabled: bool) -> Self {
+        self.strict = enabled;
+        self
+    }
+
+    /// Keep the blocks found in each file in a cache at `path`, to avoid scanning again
+    ///
+    /// On later runs, files that didn't change are not read at all, and files that
+    /// grew are scanned only from where the previous scan ended. The cache is
+    /// created if missing, and discarded if written for another network.
+    /// Blocks found in cached files were decoded according to `headers_only`
+    /// of the run that scanned them.
+    pub fn scan_cache(mut self, path: PathBuf) -> Self {
+        self.cache_path = Some(path);
+        self
+    }
+
+    /// Don't stop when the files run out, keep polling the blocks dir every `poll_interval`
+    ///
+    /// Records appended to the last file and new files are picked up as a running node
+    /// writes them, so the iterator never ends. Blocks can then go through `Reorder`,
+    /// which returns them once they are deep enough. A truncated record at the end of
+    /// the last file, including one partially written in its pre-allocated space,
+    /// is expected in this mode: it's not reported as an issue, and scanned again
+    /// once the file changes. Use [`ReadDetect::shutdown_handle`] to stop following.
+    pub fn follow(mut self, poll_interval: Duration) -> Self {
+        self.follow = Some(poll_interval);
+        self
+    }
+
+    /// Get a handle that can be used to stop following the blocks dir
+    ///
+    /// After a shutdown, `next` returns `None` instead of waiting for new blocks,
+    /// waking up if it's waiting already.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Issues found in the block files scanned so far
+    ///
+    /// With [`ReadDetect::scan_cache`], only the issues in the parts of the
+    /// files scanned by this run are reported.
+    pub fn report(&self) -> Vec<FileScanReport> {
+        self.reports.lock().unwrap().clone()
+    }
+
+    /// Find the index of the block file containing `hash`, searching from the last one
+    ///
+    /// Bypasses the scan cache, which must only hold scans done with `headers_only`
+    /// of this run.
+    fn find_file_with_block(&self, hash: &BlockHash) -> Result<usize> {
+        for (i, path) in self.paths.iter().enumerate().rev() {
+            debug!("Looking for block {} in {:?}", hash, path);
+            if scan::<F>(path, self.params.magic, true, None, 0)?
+                .blocks
+                .iter()
+                .any(|block| &block.hash == hash)
+            {
+                info!("Block {} found in {:?}", hash, path);
+                return Ok(i);
+            }
+        }
+        Err(format_err!("Block {} not found in any block file", hash))
+    }
+
+    fn start(&mut self) -> Result<()> {
+        check_genesis::<F>(self.paths.make_contiguous(), &self.params)?;
+        if let Some(path) = &self.cache_path {
+            self.cache = Some(ScanCache::open(path, self.params.magic)?);
+        }
+        if let Some(start_block) = self.start_block {
+            let found = self.find_file_with_block(&start_block)?;
+            self.paths.drain(..found.saturating_sub(1));
+        }
+        self.started = true;
+        Ok(())
+    }
+
+    /// Scan `path` for blocks not seen yet, resuming from the end of the previous scan
+    /// if it's the last scanned file
+    fn scan_file(&mut self, path: PathBuf) -> Result<()> {
+        let (file, resume_from) = match self.last.take() {
+            Some(last) if last.file.path() == path => (last.file, last.valid_end),
+            _ => (Arc::new(BlkFile::open(&path)?), 0),
+        };
+        let metadata = std::fs::metadata(&path)?;
+        let mut scanned = scan::<F>(
+            &path,
+            self.params.magic,
+            self.headers_only,
+            self.cache.as_mut(),
+            resume_from,
+        )?;
+        if self.follow.is_some() && self.paths.is_empty() {
+            // the node may be writing it right now
+            scanned
+                .issues
+                .retain(|issue| !matches!(issue, ScanIssue::TruncatedTail { .. }));
+        }
+
+        let blocks: Vec<_> = scanned
+            .blocks
+            .into_iter()
+            .filter(|e| self.seen.insert(&e.hash))
+            .collect();
+
+        {
+            let mut reports = self.reports.lock().unwrap();
+            match reports.last_mut() {
+                Some(report) if report.path == path => {
+                    report.blocks += blocks.len();
+                    report.issues.extend(scanned.issues.iter().cloned());
+                }
+                _ => reports.push(FileScanReport {
+                    path: path.clone(),
+                    blocks: blocks.len(),
+                    issues: scanned.issues.clone(),
+                }),
+            }
+        }
+        for issue in &scanned.issues {
+            if self.strict {
+                bail!("{:?}: {}", path, issue);
+            }
+            warn!("{:?}: {}", path, issue);
+        }
+
+        self.pending
+            .extend(blocks.into_iter().map(|e| e.into_fs_block(&file)));
+        self.last = Some(LastFile {
+            file,
+            valid_end: scanned.valid_end,
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+        Ok(())
+    }
+
+    /// Look for records appended to the last file and for new files
+    ///
+    /// Returns whether anything new was found.
+    fn poll(&mut self) -> Result<bool> {
+        if let Some(last) = self.last.as_ref() {
+            if last.changed()? {
+                let path = last.file.path().to_path_buf();
+                self.scan_file(path)?;
+            }
+        }
+        let last_path = self.last.as_ref().map(|last| last.file.path());
+        let new_paths: Vec<_> = list_block_files(&self.blocks_dir)?
+            .into_iter()
+            .filter(|path| Some(path.as_path()) > last_path)
+            .collect();
+        if !new_paths.is_empty() {
+            info!("{} new block files", new_paths.len());
+        }
+        self.paths.extend(new_paths);
+        Ok(!self.pending.is_empty() || !self.paths.is_empty())
     }
 }
 
-impl FallibleIterator for ReadDetect {
+impl<F: BlockFormat> FallibleIterator for ReadDetect<F> {
     type Item = FsBlock;
     type Error = anyhow::Error;
 
     fn next(&mut self) -> Result<Option<Self::Item>> {
-        self.iter.next()
+        if !self.started {
+            self.start()?;
+        }
+        loop {
+            if let Some(block) = self.pending.pop_front() {
+                return Ok(Some(block));
+            }
+            if let Some(path) = self.paths.pop_front() {
+                self.scan_file(path)?;
+                continue;
+            }
+            let poll_interval = match self.follow {
+                Some(poll_interval) if !self.shutdown.is_shutdown() => poll_interval,
+                _ => return Ok(None),
+            };
+            if !self.poll()? && self.shutdown.sleep(poll_interval) {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// The standard network of the blocks in `blocks_dir`
+///
+/// Matches the magic of the first record of the first block file against the known
+/// networks and, unless the node pruned it, checks `blk00000.dat` starts with the
+/// network's genesis block. Custom chains need explicit [`ChainParams`].
+pub fn detect_network(blocks_dir: &Path) -> Result<Network> {
+    let paths = list_block_files(blocks_dir)?;
+    let path = paths
+        .first()
+        .ok_or_else(|| format_err!("No block files in {:?}", blocks_dir))?;
+    let magic = first_magic(path)?
+        .ok_or_else(|| format_err!("No blocks in {:?} to detect the network", path))?;
+    let network = NETWORKS
+        .iter()
+        .copied()
+        .find(|network| network.magic() == magic)
+        .ok_or_else(|| {
+            format_err!(
+                "Unknown magic {:08x} in {:?}, custom chains need explicit ChainParams",
+                magic,
+                path
+            )
+        })?;
+    check_genesis::<Bitcoin>(&paths, &network.into())?;
+    info!("Detected network {} in {:?}", network, blocks_dir);
+    Ok(network)
+}
+
+const NETWORKS: [Network; 4] = [
+    Network::Bitcoin,
+    Network::Testnet,
+    Network::Signet,
+    Network::Regtest,
+];
+
+/// Check the first record of the first file in `paths` has the magic of `params`
+///
+/// An empty blocks dir or first file passes, as nothing was written yet.
+fn check_magic(paths: &[PathBuf], params: &ChainParams) -> Result<()> {
+    let path = match paths.first() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let magic = match first_magic(path)? {
+        Some(magic) => magic,
+        None => return Ok(()),
+    };
+    if magic != params.magic {
+        let network = NETWORKS.iter().find(|network| network.magic() == magic);
+        match network {
+            Some(network) => bail!("{:?} is of network {}, not the expected one", path, network),
+            None => bail!(
+                "{:?} starts with magic {:08x}, expected {:08x}",
+                path,
+                magic,
+                params.magic
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Check `blk00000.dat` starts with the genesis block of `params`, unless pruned
+fn check_genesis<F: BlockFormat>(paths: &[PathBuf], params: &ChainParams) -> Result<()> {
+    let path = match paths.first() {
+        Some(path)
+            if path
+                .file_name()
+                .map_or(false, |name| name == "blk00000.dat") =>
+        {
+            path
+        }
+        _ => return Ok(()),
+    };
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut preamble = [0u8; 8];
+    match reader.read_exact(&mut preamble) {
+        Ok(()) if preamble[..4] != [0; 4] => {}
+        Err(e) if e.kind() != ErrorKind::UnexpectedEof => return Err(e.into()),
+        // nothing written yet
+        _ => return Ok(()),
+    }
+    let header = F::decode_header(reader)?;
+    if header.hash != params.genesis_hash {
+        bail!(
+            "{:?} starts with block {}, expected genesis {}",
+            path,
+            header.hash,
+            params.genesis_hash
+        );
+    }
+    Ok(())
+}
+
+/// Magic of the record at the start of the file at `path`, if any
+fn first_magic(path: &Path) -> Result<Option<u32>> {
+    let mut bytes = [0u8; 4];
+    match File::open(path)?.read_exact(&mut bytes) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(match u32::from_le_bytes(bytes) {
+        // pre-allocated, nothing written yet
+        0 => None,
+        magic => Some(magic),
+    })
+}
+
+/// Sorted paths of the blk files in `blocks_dir`
+fn list_block_files(blocks_dir: &Path) -> Result<Vec<PathBuf>> {
+    let block_files_glob = blocks_dir.join("blk*.dat");
+    debug!("listing block files at {:?}", &block_files_glob);
+    let mut paths: Vec<PathBuf> = glob::glob(
+        block_files_glob
+            .to_str()
+            .ok_or_else(|| format_err!("Glob incorrect"))?,
+    )?
+    .collect::<std::result::Result<Vec<_>, _>>()
+    .map_err(|e| format_err!("Path error: {}", e))?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Find the blocks in the file at `path`, through the `cache` if any
+///
+/// Without a cache, scanning starts at `resume_from`. The cache resumes scanning
+/// on its own, and returns all the blocks of the file.
+fn scan<F: BlockFormat>(
+    path: &Path,
+    magic: u32,
+    headers_only: bool,
+    cache: Option<&mut ScanCache>,
+    resume_from: u64,
+) -> Result<ScannedFile> {
+    match cache {
+        Some(cache) => cache.scan::<F>(path, magic, headers_only),
+        None => {
+            let mut reader = BufReader::new(File::open(path)?);
+            reader.seek(SeekFrom::Start(resume_from))?;
+            detect_with::<F, _>(&mut reader, magic, headers_only)
+        }
+    }
+}
+
+/// Something unexpected found while scanning a blk file
+///
+/// Offsets are in bytes from the start of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanIssue {
+    /// Non-zero bytes not belonging to any record; zero bytes are the
+    /// pre-allocated tail of the file and are not reported
+    SkippedBytes { start: u64, end: u64 },
+    /// The block at `offset` parsed fine, but its size doesn't match the record preamble
+    WrongLength {
+        offset: u64,
+        declared: u32,
+        actual: u64,
+    },
+    /// The record at `offset` goes past the end of the file, or past the data
+    /// written in the pre-allocated file, as left by a node crashing while writing it
+    ///
+    /// A partially written record is only recognized when it fails to parse and
+    /// nothing but zeros follows it.
+    TruncatedTail {
+        offset: u64,
+        declared: Option<u32>,
+        available: u64,
+    },
+    /// The record at `offset` could not be decoded; it is skipped using the
+    /// size from its preamble
+    ParseError { offset: u64, error: String },
+}
+
+impl fmt::Display for ScanIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanIssue::SkippedBytes { start, end } => {
+                write!(f, "skipped {} bytes at offset {}", end - start, start)
+            }
+            ScanIssue::WrongLength {
+                offset,
+                declared,
+                actual,
+            } => write!(
+                f,
+                "record at offset {} declares {} bytes but the block is {} bytes",
+                offset, declared, actual
+            ),
+            ScanIssue::TruncatedTail {
+                offset,
+                declared: Some(declared),
+                available,
+            } => write!(
+                f,
+                "record at offset {} declares {} bytes but only {} are left in the file",
+                offset, declared, available
+            ),
+            ScanIssue::TruncatedTail { offset, .. } => {
+                write!(
+                    f,
+                    "record at offset {} is truncated in its preamble",
+                    offset
+                )
+            }
+            ScanIssue::ParseError { offset, error } => {
+                write!(f, "record at offset {} failed to parse: {}", offset, error)
+            }
+        }
     }
 }
 
-pub fn detect<R: Read + Seek>(mut reader: &mut R, magic: u32) -> Result<Vec<DetectedBlock>> {
+/// Outcome of scanning one blk file with [`detect`]
+pub struct ScannedFile {
+    pub blocks: Vec<DetectedBlock>,
+    pub issues: Vec<ScanIssue>,
+    /// End of the last complete record, where scanning can resume if the file grows
+    pub valid_end: u64,
+}
+
+/// Issues found in a blk file by [`ReadDetect`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileScanReport {
+    pub path: PathBuf,
+    pub blocks: usize,
+    pub issues: Vec<ScanIssue>,
+}
+
+/// Find all the blocks in a blk file, from the current position of `reader`
+///
+/// With `headers_only` only the headers are decoded, and the rest of each block is skipped.
+/// Corrupted or truncated records don't stop the scan, they are reported in
+/// [`ScannedFile::issues`] instead; only I/O errors are returned as `Err`.
+pub fn detect<R: Read + Seek>(
+    reader: &mut R,
+    magic: u32,
+    headers_only: bool,
+) -> Result<ScannedFile> {
+    detect_with::<Bitcoin, R>(reader, magic, headers_only)
+}
+
+/// [`detect`] for blocks encoded as `F`
+pub fn detect_with<F: BlockFormat, R: Read + Seek>(
+    mut reader: &mut R,
+    magic: u32,
+    headers_only: bool,
+) -> Result<ScannedFile> {
+    let scan_start = reader.stream_position()?;
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(scan_start))?;
+
     let mut rolling = RollingU32::default();
+    let mut issues = vec![];
+    // Non-zero bytes seen since the last record, including the magic being matched
+    let mut skipped: Option<(u64, u64)> = None;
+    // Tracked by hand, as `stream_position` on every byte would be a syscall
+    let mut pos = scan_start;
+    let mut valid_end = scan_start;
 
     // Instead of sending DetecetdBlock on the channel directly, we quickly insert in the vector
     // allowing to read ahead exactly one file (reading no block ahead cause non-parallelizing
@@ -111,39 +649,140 @@ pub fn detect<R: Read + Seek>(mut reader: &mut R, magic: u32) -> Result<Vec<Dete
     let mut detected_blocks = Vec::with_capacity(128);
 
     loop {
-        match u8::consensus_decode(&mut reader) {
-            Ok(value) => {
-                rolling.push(value);
-                if magic != rolling.as_u32() {
-                    continue;
+        let byte = match u8::consensus_decode(&mut reader) {
+            Ok(byte) => byte,
+            Err(e) if is_eof(&e) => break,
+            Err(e) => return Err(e.into()),
+        };
+        pos += 1;
+        if byte != 0 {
+            skipped = Some((skipped.map_or(pos - 1, |(start, _)| start), pos));
+        }
+        rolling.push(byte);
+        if magic != rolling.as_u32() {
+            continue;
+        }
+
+        let offset = pos - 4;
+        if let Some((start, _)) = skipped.take() {
+            if start < offset {
+                issues.push(ScanIssue::SkippedBytes { start, end: offset });
+            }
+        }
+        rolling = RollingU32::default();
+
+        let size = match u32::consensus_decode(&mut reader) {
+            Ok(size) => size,
+            Err(e) if is_eof(&e) => {
+                issues.push(ScanIssue::TruncatedTail {
+                    offset,
+                    declared: None,
+                    available: file_len - pos,
+                });
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let start = pos + 4;
+        let declared_end = start + size as u64;
+        if declared_end > file_len {
+            issues.push(ScanIssue::TruncatedTail {
+                offset,
+                declared: Some(size),
+                available: file_len - start,
+            });
+            break;
+        }
+
+        let decoded = if headers_only {
+            F::decode_header(&mut reader).map(|header| (header, declared_end))
+        } else {
+            match F::decode_block(&mut reader) {
+                Ok(block) => {
+                    let end = reader.stream_position()?;
+                    if end != declared_end {
+                        issues.push(ScanIssue::WrongLength {
+                            offset,
+                            declared: size,
+                            actual: end - start,
+                        });
+                    }
+                    Ok((F::header_info(&block), end))
                 }
+                Err(e) => Err(e),
             }
-            Err(_) => break, // EOF
         };
-        let size = u32::consensus_decode(&mut reader)?;
-        let start = reader.stream_position()? as usize;
-        match Block::consensus_decode(&mut reader) {
-            Ok(block) => {
-                let end = reader.stream_position()? as usize;
-                assert_eq!(size as usize, end - start);
-                let hash = block.header.block_hash();
-                let detected_block = DetectedBlock {
-                    start,
-                    end,
-                    hash,
-                    prev: block.header.prev_blockhash,
-                };
-                detected_blocks.push(detected_block);
+        pos = match decoded {
+            Ok((header, end)) => {
+                detected_blocks.push(DetectedBlock {
+                    start: start as usize,
+                    end: end as usize,
+                    hash: header.hash,
+                    prev: header.prev,
+                    bits: header.bits,
+                });
+                end
             }
+            Err(e) if is_io_error(&e) => return Err(e),
             Err(e) => {
-                // It's mandatory to use stream_position (require MSRV 1.51) because I can't maintain
-                // a byte read position because in case of error I don't know how many bytes of the
-                // reader has been consumed
-                error!("error block parsing {:?}", e)
+                reader.seek(SeekFrom::Start(start))?;
+                let data_end = data_end(&mut reader, start, file_len)?;
+                if data_end < declared_end {
+                    // blk files are pre-allocated, the node is still writing this record
+                    issues.push(ScanIssue::TruncatedTail {
+                        offset,
+                        declared: Some(size),
+                        available: data_end - start,
+                    });
+                    break;
+                }
+                issues.push(ScanIssue::ParseError {
+                    offset,
+                    error: e.to_string(),
+                });
+                declared_end
             }
+        };
+        reader.seek(SeekFrom::Start(pos))?;
+        valid_end = pos;
+    }
+
+    if let Some((start, end)) = skipped {
+        issues.push(ScanIssue::SkippedBytes { start, end });
+    }
+
+    Ok(ScannedFile {
+        blocks: detected_blocks,
+        issues,
+        valid_end,
+    })
+}
+
+/// End of the last non-zero byte from `pos`, the position of `reader`, to `file_len`
+///
+/// Returns `pos` if there are only zeros.
+fn data_end<R: Read>(reader: &mut R, mut pos: u64, file_len: u64) -> io::Result<u64> {
+    let mut data_end = pos;
+    let mut buf = [0u8; 4096];
+    while pos < file_len {
+        let len = ((file_len - pos) as usize).min(buf.len());
+        reader.read_exact(&mut buf[..len])?;
+        if let Some(i) = buf[..len].iter().rposition(|byte| *byte != 0) {
+            data_end = pos + i as u64 + 1;
         }
+        pos += len as u64;
     }
-    Ok(detected_blocks)
+    Ok(data_end)
+}
+
+fn is_eof(e: &encode::Error) -> bool {
+    matches!(e, encode::Error::Io(e) if e.kind() == ErrorKind::UnexpectedEof)
+}
+
+/// Whether decoding failed on reading, rather than on an invalid or truncated block
+fn is_io_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .map_or(false, |e| e.kind() != ErrorKind::UnexpectedEof)
 }
 
 /// Implements a rolling u32, every time a new u8 is `push`ed the old value is shifted by 1 byte
@@ -162,7 +801,165 @@ impl RollingU32 {
 
 #[cfg(test)]
 mod test {
-    use super::RollingU32;
+    use super::{detect, detect_network, ReadDetect, RollingU32, ScanIssue};
+    use crate::test_util::{block_record, record, regtest_chain, write_blk, TempDir};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::serialize;
+    use bitcoin::Network;
+    use fallible_iterator::FallibleIterator;
+    use std::io::{Cursor, Write};
+    use std::time::Duration;
+
+    #[test]
+    fn scan_issues() {
+        let magic = Network::Regtest.magic();
+        let block = serialize(&genesis_block(Network::Regtest));
+        let len = block.len() as u32;
+
+        let mut file = record(magic, len, &block);
+        file.extend([0xAA, 0xBB, 0x00, 0xCC]);
+        let wrong_length_offset = file.len() as u64;
+        file.extend(record(magic, len + 10, &block));
+        let valid_end = file.len() as u64;
+        file.extend([0u8; 10]);
+        let truncated_offset = file.len() as u64;
+        file.extend(record(magic, len, &block[..10]));
+        file.extend([0u8; 5]);
+
+        let scanned = detect(&mut Cursor::new(&file), magic, false).unwrap();
+        assert_eq!(scanned.blocks.len(), 2);
+        assert_eq!(scanned.valid_end, valid_end);
+        assert_eq!(
+            scanned.issues,
+            vec![
+                ScanIssue::SkippedBytes {
+                    start: len as u64 + 8,
+                    end: wrong_length_offset,
+                },
+                ScanIssue::WrongLength {
+                    offset: wrong_length_offset,
+                    declared: len + 10,
+                    actual: len as u64,
+                },
+                ScanIssue::TruncatedTail {
+                    offset: truncated_offset,
+                    declared: Some(len),
+                    available: 15,
+                },
+            ]
+        );
+
+        let scanned = detect(&mut Cursor::new(&file), magic, true).unwrap();
+        assert_eq!(scanned.blocks.len(), 2);
+        assert_eq!(scanned.issues.len(), 2);
+    }
+
+    #[test]
+    fn zero_padded_partial_record() {
+        let magic = Network::Regtest.magic();
+        let block = serialize(&genesis_block(Network::Regtest));
+        let len = block.len() as u32;
+
+        let mut file = record(magic, len, &block);
+        let valid_end = file.len() as u64;
+        // only the header and the transaction count written in the pre-allocated file
+        file.extend(record(magic, len, &block[..81]));
+        file.extend(vec![0u8; 1000]);
+
+        let scanned = detect(&mut Cursor::new(&file), magic, false).unwrap();
+        assert_eq!(scanned.blocks.len(), 1);
+        assert_eq!(scanned.valid_end, valid_end);
+        assert_eq!(
+            scanned.issues,
+            vec![ScanIssue::TruncatedTail {
+                offset: valid_end,
+                declared: Some(len),
+                available: 81,
+            }]
+        );
+
+        // the rest of the record written, resuming from `valid_end` finds it
+        let start = valid_end as usize + 8;
+        file[start..start + block.len()].copy_from_slice(&block);
+        let mut cursor = Cursor::new(&file);
+        cursor.set_position(valid_end);
+        let scanned = detect(&mut cursor, magic, false).unwrap();
+        assert_eq!(scanned.blocks.len(), 1);
+        assert!(scanned.issues.is_empty());
+        assert_eq!(scanned.valid_end, (start + block.len()) as u64);
+    }
+
+    #[test]
+    fn follow() {
+        let dir = TempDir::new();
+        let chain = regtest_chain(3);
+
+        write_blk(&dir, 0, &chain[..1]);
+        let mut read_detect = ReadDetect::new(&dir, Network::Regtest)
+            .unwrap()
+            .follow(Duration::from_millis(1));
+        assert_eq!(
+            read_detect.next().unwrap().unwrap().hash,
+            chain[0].block_hash()
+        );
+
+        let mut bytes = block_record(&chain[1]);
+        bytes.extend([0u8; 100]);
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("blk00000.dat"))
+            .unwrap();
+        file.write_all(&bytes).unwrap();
+        drop(file);
+        assert_eq!(
+            read_detect.next().unwrap().unwrap().hash,
+            chain[1].block_hash()
+        );
+
+        write_blk(&dir, 1, &chain[2..]);
+        assert_eq!(
+            read_detect.next().unwrap().unwrap().hash,
+            chain[2].block_hash()
+        );
+        assert_eq!(read_detect.report().len(), 2);
+        assert!(read_detect.report().iter().all(|r| r.issues.is_empty()));
+
+        read_detect.shutdown_handle().shutdown();
+        assert!(read_detect.next().unwrap().is_none());
+    }
+
+    #[test]
+    fn follow_shutdown_while_waiting() {
+        let dir = TempDir::new();
+        write_blk(&dir, 0, &regtest_chain(1));
+        let mut read_detect = ReadDetect::new(&dir, Network::Regtest)
+            .unwrap()
+            .follow(Duration::from_secs(3600));
+        assert!(read_detect.next().unwrap().is_some());
+
+        let shutdown = read_detect.shutdown_handle();
+        let waiting = std::thread::spawn(move || read_detect.next().unwrap().is_none());
+        std::thread::sleep(Duration::from_millis(50));
+        shutdown.shutdown();
+        assert!(waiting.join().unwrap());
+    }
+
+    #[test]
+    fn detect_network_from_dir() {
+        let dir = TempDir::new();
+        write_blk(&dir, 0, &regtest_chain(1));
+
+        assert_eq!(detect_network(&dir).unwrap(), Network::Regtest);
+        assert!(ReadDetect::new(&dir, Network::Bitcoin).is_err());
+        let read_detect = ReadDetect::from_dir(&dir).unwrap();
+        assert_eq!(read_detect.chain_params(), Network::Regtest.into());
+
+        // blocks of another regtest fork
+        let mut fork = genesis_block(Network::Regtest);
+        fork.header.nonce += 1;
+        write_blk(&dir, 0, &[fork]);
+        assert!(detect_network(&dir).is_err());
+    }
 
     #[test]
     fn test_rolling() {
@@ -0,0 +1,268 @@
+use super::{detect_with, DetectedBlock, ScannedFile};
+use crate::source::format::BlockFormat;
+use anyhow::{format_err, Result};
+use bitcoin::consensus::{encode, Decodable, Encodable};
+use bitcoin::{BlockHash, VarInt};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Bumped on any change of the layout, older caches are discarded
+const VERSION: u32 = 1;
+
+/// What's known about a blk file, as of the last scan
+struct Entry {
+    len: u64,
+    /// Nanoseconds since the unix epoch
+    modified: u64,
+    /// Where the scan can resume if the file grows
+    valid_end: u64,
+    blocks: Vec<DetectedBlock>,
+}
+
+/// Blocks found in each blk file, persisted in a sidecar file
+///
+/// The file starts with the version and the network magic, followed by entries
+/// keyed by the blk file name. Entries are only ever appended, a later entry
+/// replaces an earlier one for the same blk file. Superseded entries are dropped
+/// by rewriting the file when it's opened.
+pub(super) struct ScanCache {
+    path: PathBuf,
+    file: File,
+    magic: u32,
+    entries: HashMap<String, Entry>,
+}
+
+impl ScanCache {
+    pub(super) fn open(path: &Path, magic: u32) -> Result<Self> {
+        let mut entries = HashMap::new();
+        let mut superseded = 0usize;
+        let mut valid = false;
+
+        if let Ok(bytes) = std::fs::read(path) {
+            let mut cursor = Cursor::new(&bytes);
+            if read_preamble(&mut cursor) == Some((VERSION, magic)) {
+                valid = true;
+                while (cursor.position() as usize) < bytes.len() {
+                    match read_entry(&mut cursor) {
+                        Ok((name, entry)) => {
+                            if entries.insert(name, entry).is_some() {
+                                superseded += 1;
+                            }
+                        }
+                        Err(e) => {
+                            // a run interrupted while appending, what's before is fine
+                            warn!("Scan cache {:?} truncated: {}", path, e);
+                            superseded += 1;
+                            break;
+                        }
+                    }
+                }
+            } else if !bytes.is_empty() {
+                info!("Scan cache {:?} is for another version or network", path);
+            }
+        }
+        info!("Scan cache {:?} has {} block files", path, entries.len());
+
+        let mut cache = Self {
+            path: path.to_path_buf(),
+            file: OpenOptions::new().append(true).create(true).open(path)?,
+            magic,
+            entries,
+        };
+        if !valid || superseded > 0 {
+            cache.rewrite()?;
+        }
+        Ok(cache)
+    }
+
+    /// Write the cache again with only the current entries
+    fn rewrite(&mut self) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut bytes = vec![];
+        VERSION.consensus_encode(&mut bytes)?;
+        self.magic.consensus_encode(&mut bytes)?;
+        for (name, entry) in self.entries.iter() {
+            write_entry(&mut bytes, name, entry)?;
+        }
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    /// Find the blocks in the blk file at `path`, scanning only what's not in the cache
+    ///
+    /// A cached entry is used as is if the size and modification time of the file
+    /// didn't change. Otherwise, if the last cached block is still in place, the file
+    /// is scanned from the end of the last cached record, as Bitcoin Core only appends
+    /// to blk files. Anything else triggers a scan of the whole file.
+    pub(super) fn scan<F: BlockFormat>(
+        &mut self,
+        path: &Path,
+        magic: u32,
+        headers_only: bool,
+    ) -> Result<ScannedFile> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format_err!("Invalid block file name {:?}", path))?
+            .to_string();
+        let metadata = std::fs::metadata(path)?;
+        let len = metadata.len();
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut blocks = match self.entries.remove(&name) {
+            Some(entry) if entry.len == len && entry.modified == modified => {
+                debug!("{:?} unchanged", path);
+                let scanned = ScannedFile {
+                    blocks: entry.blocks.clone(),
+                    issues: vec![],
+                    valid_end: entry.valid_end,
+                };
+                self.entries.insert(name, entry);
+                return Ok(scanned);
+            }
+            Some(entry)
+                if entry.valid_end <= len && last_block_in_place::<F, _>(&mut reader, &entry)? =>
+            {
+                debug!("{:?} changed, scanning from {}", path, entry.valid_end);
+                reader.seek(SeekFrom::Start(entry.valid_end))?;
+                entry.blocks
+            }
+            _ => {
+                debug!("{:?} not cached, scanning", path);
+                reader.seek(SeekFrom::Start(0))?;
+                vec![]
+            }
+        };
+
+        let mut scanned = detect_with::<F, _>(&mut reader, magic, headers_only)?;
+        blocks.append(&mut scanned.blocks);
+        let entry = Entry {
+            len,
+            modified,
+            valid_end: scanned.valid_end,
+            blocks,
+        };
+
+        let mut bytes = vec![];
+        write_entry(&mut bytes, &name, &entry)?;
+        self.file.write_all(&bytes)?;
+
+        scanned.blocks = entry.blocks.clone();
+        self.entries.insert(name, entry);
+        Ok(scanned)
+    }
+}
+
+/// Check the header of the last cached block is still there, in case the modification
+/// time changed for other reasons than appending, like copying the blocks dir
+fn last_block_in_place<F: BlockFormat, R: Read + Seek>(
+    reader: &mut R,
+    entry: &Entry,
+) -> Result<bool> {
+    let last = match entry.blocks.last() {
+        Some(last) => last,
+        None => return Ok(false),
+    };
+    reader.seek(SeekFrom::Start(last.start as u64))?;
+    Ok(match F::decode_header(reader) {
+        Ok(header) => header.hash == last.hash,
+        Err(_) => false,
+    })
+}
+
+fn read_preamble<R: Read>(mut reader: R) -> Option<(u32, u32)> {
+    let version = u32::consensus_decode(&mut reader).ok()?;
+    let magic = u32::consensus_decode(&mut reader).ok()?;
+    Some((version, magic))
+}
+
+fn write_entry<W: Write>(mut writer: W, name: &str, entry: &Entry) -> Result<()> {
+    name.to_string().consensus_encode(&mut writer)?;
+    entry.len.consensus_encode(&mut writer)?;
+    entry.modified.consensus_encode(&mut writer)?;
+    entry.valid_end.consensus_encode(&mut writer)?;
+    VarInt(entry.blocks.len() as u64).consensus_encode(&mut writer)?;
+    for block in entry.blocks.iter() {
+        (block.start as u64).consensus_encode(&mut writer)?;
+        (block.end as u64).consensus_encode(&mut writer)?;
+        block.hash.consensus_encode(&mut writer)?;
+        block.prev.consensus_encode(&mut writer)?;
+        block.bits.consensus_encode(&mut writer)?;
+    }
+    Ok(())
+}
+
+fn read_entry<R: Read>(mut reader: R) -> Result<(String, Entry), encode::Error> {
+    let name = String::consensus_decode(&mut reader)?;
+    let len = u64::consensus_decode(&mut reader)?;
+    let modified = u64::consensus_decode(&mut reader)?;
+    let valid_end = u64::consensus_decode(&mut reader)?;
+    let count = VarInt::consensus_decode(&mut reader)?.0;
+    let mut blocks = Vec::with_capacity(count.min(1024) as usize);
+    for _ in 0..count {
+        blocks.push(DetectedBlock {
+            start: u64::consensus_decode(&mut reader)? as usize,
+            end: u64::consensus_decode(&mut reader)? as usize,
+            hash: BlockHash::consensus_decode(&mut reader)?,
+            prev: BlockHash::consensus_decode(&mut reader)?,
+            bits: u32::consensus_decode(&mut reader)?,
+        });
+    }
+    Ok((
+        name,
+        Entry {
+            len,
+            modified,
+            valid_end,
+            blocks,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::ScanCache;
+    use crate::source::format::Bitcoin;
+    use crate::test_util::{block_record, regtest_chain, TempDir};
+    use bitcoin::Network;
+    use std::io::Write;
+
+    #[test]
+    fn rescan_grown_file() {
+        let dir = TempDir::new();
+        let magic = Network::Regtest.magic();
+        let record = block_record(&regtest_chain(1)[0]);
+
+        let blk = dir.join("blk00000.dat");
+        let cache_path = dir.join("scan.cache");
+        std::fs::write(&blk, &record).unwrap();
+
+        let mut cache = ScanCache::open(&cache_path, magic).unwrap();
+        let scanned = cache.scan::<Bitcoin>(&blk, magic, false).unwrap();
+        assert_eq!(scanned.blocks.len(), 1);
+        assert_eq!(scanned.valid_end, record.len() as u64);
+        drop(cache);
+
+        let mut file = std::fs::OpenOptions::new().append(true).open(&blk).unwrap();
+        file.write_all(&record).unwrap();
+        drop(file);
+
+        let mut cache = ScanCache::open(&cache_path, magic).unwrap();
+        let scanned = cache.scan::<Bitcoin>(&blk, magic, false).unwrap();
+        assert_eq!(scanned.blocks.len(), 2);
+        assert_eq!(scanned.blocks[1].start, record.len() + 8);
+        drop(cache);
+
+        let mut cache = ScanCache::open(&cache_path, magic).unwrap();
+        assert_eq!(cache.entries.len(), 1);
+        let scanned = cache.scan::<Bitcoin>(&blk, magic, true).unwrap();
+        assert_eq!(scanned.blocks.len(), 2);
+    }
+}
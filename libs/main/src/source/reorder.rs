@@ -1,141 +1,961 @@
-use super::{block_extra::BlockExtra, FsBlock};
+use super::{block_extra::BlockExtra, FromFsBlock, FsBlock};
 use anyhow::Result;
-use bitcoin::blockdata::constants::genesis_block;
-use bitcoin::{BlockHash, Network};
-use block_iter_core::BlockHeight;
+use bitcoin::util::uint::Uint256;
+use bitcoin::{BlockHash, BlockHeader, TxMerkleNode};
+use block_iter_core::{BlockHeight, BlockHeightAndHash, ChainParams};
 use fallible_iterator::FallibleIterator;
-use log::warn;
-use std::collections::HashMap;
-use std::convert::TryInto;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::marker::PhantomData;
+
+mod tree;
+
+pub use tree::BlockTree;
+
+/// Default limit of blocks buffered while waiting for the next block of the chain
+///
+/// Even tough should be 1024 -> https://github.com/bitcoin/bitcoin/search?q=BLOCK_DOWNLOAD_WINDOW
+/// in practice it needs to be greater.
+pub const DEFAULT_MAX_BUFFERED: usize = 10_000;
+
+/// How many of the biggest subtrees are reported in [`ReorderBufferFull`]
+const REPORTED_SUBTREES: usize = 5;
+
+/// How many of the last yielded blocks are remembered, to recognize
+/// stale blocks forking off them that are read after them
+const MAIN_CHAIN_WINDOW: usize = 2048;
+
+/// A block that is not in the yielded chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaleBlock {
+    pub height: BlockHeight,
+    pub hash: BlockHash,
+    pub prev: BlockHash,
+    /// The last block of the yielded chain this block descends from
+    pub fork_point: BlockHeightAndHash,
+}
+
+/// Buffered blocks that can't be connected to the chain yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrphanSubtree {
+    /// The lowest block of the subtree
+    pub root: BlockHash,
+    /// The parent of `root`, not seen yet
+    pub missing_prev: BlockHash,
+    /// Number of blocks in the subtree
+    pub size: usize,
+}
+
+/// Error returned by [`Reorder`] when too many blocks are buffered
+///
+/// Usually means the next block of the chain is missing from the blk files,
+/// or they are more out of order than [`Reorder::max_buffered`] allows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReorderBufferFull {
+    pub limit: usize,
+    /// Hash of the next block of the chain, the one we've been waiting for
+    pub next: BlockHash,
+    /// The biggest subtrees of the buffered blocks, biggest first
+    pub largest_subtrees: Vec<OrphanSubtree>,
+}
+
+impl fmt::Display for ReorderBufferFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Reorder buffer grew over {} blocks waiting for block {}; biggest subtrees:",
+            self.limit, self.next
+        )?;
+        for subtree in &self.largest_subtrees {
+            write!(
+                f,
+                " {} blocks from {} (missing prev {});",
+                subtree.size, subtree.root, subtree.missing_prev
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ReorderBufferFull {}
+
+/// What [`Reorder`] does with the buffered blocks when the input ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndOfInput {
+    /// Stop; blocks with less than `max_reorg` blocks on top are never yielded
+    Stop,
+    /// Yield the rest of the most-work buffered chain, up to its tip
+    ///
+    /// See [`Reorder::last_unconfirmed_depth`].
+    FlushTip,
+}
+
+impl Default for EndOfInput {
+    fn default() -> Self {
+        EndOfInput::Stop
+    }
+}
+
+/// A buffered block in the tree of blocks waiting to be yielded
+///
+/// Every node tracks the most-work chain of its buffered descendants.
+/// It depends only on the descendants, so it's updated incrementally,
+/// walking up from every added block for as long as the best chain changes.
+struct Node {
+    prev: BlockHash,
+    next: Vec<BlockHash>,
+    work: Uint256,
+    /// Work of this block and the best chain of descendants on top of it
+    best_work: Uint256,
+    /// Number of blocks in that chain, including this one
+    best_len: u32,
+    /// First block of that chain
+    best_next: Option<BlockHash>,
+}
+
+/// Work needed to mine a block with the given difficulty target
+fn work_from_bits(bits: u32) -> Uint256 {
+    BlockHeader {
+        version: 0,
+        prev_blockhash: BlockHash::default(),
+        merkle_root: TxMerkleNode::default(),
+        time: 0,
+        bits,
+        nonce: 0,
+    }
+    .work()
+}
 
 struct OutOfOrderBlocks {
-    blocks: HashMap<BlockHash, FsBlock>,
+    nodes: HashMap<BlockHash, Node>,
+    stored: HashMap<BlockHash, FsBlock>,
     follows: HashMap<BlockHash, Vec<BlockHash>>,
+    /// Ancestors of the start block and their other descendants, see
+    /// [`Reorder::start_after`]; blocks building on them are dropped on arrival
+    forgotten: HashSet<BlockHash>,
     max_reorg: u8,
 }
 
 impl OutOfOrderBlocks {
     fn new(max_reorg: u8) -> Self {
         OutOfOrderBlocks {
-            blocks: HashMap::default(),
+            nodes: HashMap::default(),
+            stored: HashMap::default(),
             follows: HashMap::default(),
+            forgotten: HashSet::default(),
             max_reorg,
         }
     }
 
-    fn add(&mut self, mut raw_block: FsBlock) {
+    fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn add(&mut self, raw_block: FsBlock) {
+        let hash = raw_block.hash;
         let prev_hash = raw_block.prev;
-        self.follows
-            .entry(prev_hash)
-            .or_default()
-            .push(raw_block.hash);
-
-        if let Some(follows) = self.follows.remove(&raw_block.hash) {
-            for el in follows {
-                raw_block.next.push(el);
-            }
+        self.follows.entry(prev_hash).or_default().push(hash);
+
+        let mut next = raw_block.next.clone();
+        if let Some(follows) = self.follows.remove(&hash) {
+            next.extend(follows);
         }
 
-        if let Some(prev_block) = self.blocks.get_mut(&prev_hash) {
-            prev_block.next.push(raw_block.hash);
+        if let Some(prev_block) = self.nodes.get_mut(&prev_hash) {
+            prev_block.next.push(hash);
         }
 
-        self.blocks.insert(raw_block.hash, raw_block);
-    }
+        let work = work_from_bits(raw_block.bits);
+        self.nodes.insert(
+            hash,
+            Node {
+                prev: prev_hash,
+                next,
+                work,
+                best_work: work,
+                best_len: 1,
+                best_next: None,
+            },
+        );
+        self.stored.insert(hash, raw_block);
 
-    /// check the block identified by `hash` has at least `self.max_reorgs` blocks after, to be sure it's not a reorged block
-    /// keep track of the followed `path` that should be initialized with empty vec in the first call
-    fn exist_and_has_followers(&self, hash: &BlockHash, path: Vec<BlockHash>) -> Option<BlockHash> {
-        if path.len() == self.max_reorg as usize {
-            return Some(path[0]);
+        // children read before this block might be buffered already
+        self.update_best(&hash);
+        // propagate the new best chain to the ancestors
+        let mut cur = prev_hash;
+        while self.update_best(&cur) {
+            cur = self.nodes[&cur].prev;
         }
-        if let Some(block) = self.blocks.get(hash) {
-            for next in block.next.iter() {
-                let mut path = path.clone();
-                path.push(*next);
-                if let Some(hash) = self.exist_and_has_followers(next, path) {
-                    return Some(hash);
+    }
+
+    /// Recalculate the best chain on top of `hash` from its children
+    ///
+    /// Returns `true` if it changed. On equal work the first seen child wins.
+    fn update_best(&mut self, hash: &BlockHash) -> bool {
+        let node = if let Some(node) = self.nodes.get(hash) {
+            node
+        } else {
+            return false;
+        };
+        let mut best: Option<(BlockHash, Uint256, u32)> = None;
+        for next in &node.next {
+            if let Some(child) = self.nodes.get(next) {
+                if best.map_or(true, |(_, work, _)| work < child.best_work) {
+                    best = Some((*next, child.best_work, child.best_len));
                 }
             }
         }
-        None
+        let (best_next, best_work, best_len) = match best {
+            Some((next, work, len)) => (Some(next), node.work + work, len + 1),
+            None => (None, node.work, 1),
+        };
+
+        let node = self.nodes.get_mut(hash).expect("exists");
+        if node.best_next == best_next && node.best_work == best_work && node.best_len == best_len {
+            return false;
+        }
+        node.best_next = best_next;
+        node.best_work = best_work;
+        node.best_len = best_len;
+        true
     }
 
-    fn remove(&mut self, hash: &BlockHash) -> Option<FsBlock> {
-        if let Some(next) = self.exist_and_has_followers(hash, vec![]) {
-            let mut value = self.blocks.remove(hash).unwrap();
-            if value.next.len() > 1 {
-                warn!("at {} fork to {:?} took {}", value.hash, value.next, next);
+    /// The next block on the most-work chain on top of `hash`, if there are
+    /// at least `self.max_reorg` blocks in it, to be sure it's not a reorged block
+    fn confirmed_next(&self, hash: &BlockHash) -> Option<BlockHash> {
+        let node = self.nodes.get(hash)?;
+        if u32::from(self.max_reorg) < node.best_len {
+            node.best_next
+        } else {
+            None
+        }
+    }
+
+    /// Remove the block if its next block is confirmed
+    ///
+    /// Returns the other children of the block too, which are stale.
+    fn remove(&mut self, hash: &BlockHash) -> Option<(FsBlock, Vec<BlockHash>)> {
+        if let Some(next) = self.confirmed_next(hash) {
+            let node = self.nodes.remove(hash).expect("exists");
+            let mut value = self.stored.remove(hash).expect("stored with node");
+            if node.next.len() > 1 {
+                warn!("at {} fork to {:?} took {}", value.hash, node.next, next);
             }
+            let stale = node.next.into_iter().filter(|h| *h != next).collect();
             value.next = vec![next];
-            Some(value)
+            Some((value, stale))
         } else {
             None
         }
     }
+
+    /// Remove the block, returning its prev and children
+    fn take_node(&mut self, hash: &BlockHash) -> Option<(BlockHash, Vec<BlockHash>)> {
+        self.follows.remove(hash);
+        let node = self.nodes.remove(hash)?;
+        self.stored.remove(hash);
+        Some((node.prev, node.next))
+    }
+
+    /// Remove the block regardless of the number of its followers
+    fn take(&mut self, hash: &BlockHash) -> Option<FsBlock> {
+        self.nodes.remove(hash)?;
+        self.stored.remove(hash)
+    }
+
+    /// The most-work chain of buffered blocks starting at `hash`, including it
+    fn best_path(&self, hash: &BlockHash) -> VecDeque<BlockHash> {
+        let mut path = VecDeque::new();
+        let mut cur = *hash;
+        while let Some(node) = self.nodes.get(&cur) {
+            path.push_back(cur);
+            match node.best_next {
+                Some(next) => cur = next,
+                None => break,
+            }
+        }
+        path
+    }
+
+    fn forget(&mut self, hash: &BlockHash) {
+        self.nodes.remove(hash);
+        self.stored.remove(hash);
+    }
+
+    /// Forget the block and all its buffered descendants
+    fn forget_subtree(&mut self, hash: BlockHash) {
+        let mut stack = vec![hash];
+        while let Some(hash) = stack.pop() {
+            if !self.forgotten.insert(hash) {
+                continue;
+            }
+            stack.extend(self.follows.remove(&hash).into_iter().flatten());
+            if let Some(node) = self.nodes.remove(&hash) {
+                stack.extend(node.next);
+            }
+            self.stored.remove(&hash);
+        }
+    }
+
+    /// Forget `hash`, the parent of `child`, with all its buffered ancestors
+    /// and all their descendants except `child`
+    ///
+    /// Returns the hash of the first ancestor not buffered yet.
+    fn forget_with_ancestors(&mut self, mut hash: BlockHash, mut child: BlockHash) -> BlockHash {
+        loop {
+            self.forgotten.insert(hash);
+            let mut children = self.follows.remove(&hash).unwrap_or_default();
+            let node = self.nodes.remove(&hash);
+            self.stored.remove(&hash);
+            if let Some(node) = node.as_ref() {
+                children.extend(node.next.iter().copied());
+            }
+            for other in children.into_iter().filter(|other| *other != child) {
+                self.forget_subtree(other);
+            }
+
+            match node {
+                Some(node) => {
+                    child = hash;
+                    hash = node.prev;
+                }
+                None => return hash,
+            }
+        }
+    }
+
+    /// Find the biggest subtrees of blocks whose parent is not buffered
+    fn largest_subtrees(&self, count: usize) -> Vec<OrphanSubtree> {
+        let mut subtrees: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| !self.nodes.contains_key(&node.prev))
+            .map(|(hash, node)| {
+                let mut size = 0;
+                let mut stack = vec![*hash];
+                while let Some(hash) = stack.pop() {
+                    if let Some(node) = self.nodes.get(&hash) {
+                        size += 1;
+                        stack.extend(node.next.iter().copied());
+                    }
+                }
+                OrphanSubtree {
+                    root: *hash,
+                    missing_prev: node.prev,
+                    size,
+                }
+            })
+            .collect();
+        subtrees.sort_by(|a, b| b.size.cmp(&a.size));
+        subtrees.truncate(count);
+        subtrees
+    }
 }
 
-pub struct Reorder<I> {
+/// Reorders blocks read from the blk files, yielding them in the chain order
+///
+/// `O` is the type of the yielded items: [`BlockExtra`] by default, use
+/// [`Reorder::with_output`] for other ones, e.g. `WithHeightAndId<BlockHeader>`
+/// to never read block bodies.
+///
+/// Starts at the genesis block, unless [`Reorder::start_after`] is used.
+pub struct Reorder<I, O = BlockExtra> {
     iter: I,
     height: BlockHeight,
     next: BlockHash,
     blocks: OutOfOrderBlocks,
+    max_buffered: usize,
+    /// Block to start after; not yielded, as it was processed already
+    start_after: Option<BlockHash>,
+    /// Highest ancestor of `start_after` not seen yet; dropped on arrival
+    ancestors_frontier: Option<BlockHash>,
+    end_of_input: EndOfInput,
+    /// Rest of the chain to yield, once the input ended
+    flush_path: Option<VecDeque<BlockHash>>,
+    last_unconfirmed_depth: Option<u32>,
+    /// Recently yielded blocks: height and the next block
+    main_chain: HashMap<BlockHash, (BlockHeight, Option<BlockHash>)>,
+    main_chain_order: VecDeque<BlockHash>,
+    /// All the stale blocks seen: height and fork point
+    stale_known: HashMap<BlockHash, (BlockHeight, BlockHeightAndHash)>,
+    stale_blocks: Vec<StaleBlock>,
+    tree: Option<BlockTree>,
+    _output: PhantomData<fn() -> O>,
 }
 
 impl<I> Reorder<I>
 where
     I: FallibleIterator<Item = FsBlock>,
 {
-    pub fn new(network: Network, max_reorg: u8, iter: I) -> Self {
+    /// Reorder blocks of the chain with `params`, e.g. a `bitcoin::Network`
+    ///
+    /// See [`ChainParams::max_reorg_hint`] for a sensible `max_reorg`.
+    pub fn new(params: impl Into<ChainParams>, max_reorg: u8, iter: I) -> Self {
         Self {
             height: 0,
-            next: genesis_block(network).block_hash(),
+            next: params.into().genesis_hash,
             blocks: OutOfOrderBlocks::new(max_reorg),
+            max_buffered: DEFAULT_MAX_BUFFERED,
+            start_after: None,
+            ancestors_frontier: None,
+            end_of_input: EndOfInput::default(),
+            flush_path: None,
+            last_unconfirmed_depth: None,
+            main_chain: HashMap::default(),
+            main_chain_order: VecDeque::default(),
+            stale_known: HashMap::default(),
+            stale_blocks: vec![],
+            tree: None,
             iter,
+            _output: PhantomData,
         }
     }
 }
 
-impl<I> FallibleIterator for Reorder<I>
+impl<I, O> Reorder<I, O> {
+    /// Change the type of the yielded items
+    pub fn with_output<P>(self) -> Reorder<I, P>
+    where
+        P: FromFsBlock,
+    {
+        Reorder {
+            iter: self.iter,
+            height: self.height,
+            next: self.next,
+            blocks: self.blocks,
+            max_buffered: self.max_buffered,
+            start_after: self.start_after,
+            ancestors_frontier: self.ancestors_frontier,
+            end_of_input: self.end_of_input,
+            flush_path: self.flush_path,
+            last_unconfirmed_depth: self.last_unconfirmed_depth,
+            main_chain: self.main_chain,
+            main_chain_order: self.main_chain_order,
+            stale_known: self.stale_known,
+            stale_blocks: self.stale_blocks,
+            tree: self.tree,
+            _output: PhantomData,
+        }
+    }
+
+    /// Start after a block that was already processed, instead of at the genesis block
+    ///
+    /// The first yielded block is the child of `hash`, at `height + 1`.
+    /// Ancestors of `hash` and blocks forking off them are dropped as soon as
+    /// they are recognized as such, but blocks read before `hash` itself is
+    /// found have to be buffered, so use it with `ReadDetect::start_from_block`
+    /// to skip most of them. The hashes of the dropped blocks are remembered,
+    /// to drop their descendants read later too.
+    /// Those blocks don't count against [`Reorder::max_buffered`], as the files
+    /// read before `hash` can hold many more blocks than the limit.
+    pub fn start_after(mut self, height: BlockHeight, hash: BlockHash) -> Self {
+        assert_eq!(
+            self.blocks.len(),
+            0,
+            "must be called before reading any blocks"
+        );
+        self.height = height;
+        self.next = hash;
+        self.start_after = Some(hash);
+        self
+    }
+
+    /// Set what to do with the buffered blocks when the input ends
+    ///
+    /// The default is [`EndOfInput::Stop`].
+    pub fn end_of_input(mut self, end_of_input: EndOfInput) -> Self {
+        self.end_of_input = end_of_input;
+        self
+    }
+
+    /// Number of blocks on top of the last yielded block, if it had less than `max_reorg`
+    ///
+    /// Only blocks yielded with [`EndOfInput::FlushTip`] can be unconfirmed like that;
+    /// they might still be reorged away, e.g. after the node writes more blocks.
+    pub fn last_unconfirmed_depth(&self) -> Option<u32> {
+        self.last_unconfirmed_depth
+    }
+
+    /// Take the stale blocks found since the last call
+    ///
+    /// Blocks on branches that lost to the yielded chain, with their heights
+    /// and the fork point, in the order they were recognized as stale.
+    pub fn take_stale_blocks(&mut self) -> Vec<StaleBlock> {
+        std::mem::take(&mut self.stale_blocks)
+    }
+
+    /// Record the tree of all the yielded and stale blocks, see [`Reorder::block_tree`]
+    ///
+    /// Takes some memory for every block, so it's disabled by default.
+    pub fn record_tree(mut self, enabled: bool) -> Self {
+        self.tree = if enabled {
+            Some(BlockTree::default())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// The tree of blocks seen so far, if enabled with [`Reorder::record_tree`]
+    pub fn block_tree(&self) -> Option<&BlockTree> {
+        self.tree.as_ref()
+    }
+
+    fn record_main_chain(
+        &mut self,
+        height: BlockHeight,
+        hash: BlockHash,
+        prev: BlockHash,
+        next: Option<BlockHash>,
+    ) {
+        self.main_chain.insert(hash, (height, next));
+        self.main_chain_order.push_back(hash);
+        if MAIN_CHAIN_WINDOW < self.main_chain_order.len() {
+            let oldest = self.main_chain_order.pop_front().expect("not empty");
+            self.main_chain.remove(&oldest);
+        }
+        if let Some(tree) = self.tree.as_mut() {
+            tree.insert(height, hash, prev, false);
+        }
+    }
+
+    fn record_stale(&mut self, stale: StaleBlock) {
+        info!(
+            "Stale block {}H {} forked at {}H {}",
+            stale.height, stale.hash, stale.fork_point.height, stale.fork_point.hash
+        );
+        self.stale_known
+            .insert(stale.hash, (stale.height, stale.fork_point));
+        if let Some(tree) = self.tree.as_mut() {
+            tree.insert(stale.height, stale.hash, stale.prev, true);
+        }
+        self.stale_blocks.push(stale);
+    }
+
+    /// Remove the buffered subtrees starting at `roots` as stale
+    fn remove_stale(&mut self, roots: Vec<BlockHash>, fork_point: BlockHeightAndHash) {
+        let mut stack: Vec<_> = roots
+            .into_iter()
+            .map(|hash| (hash, fork_point.height + 1))
+            .collect();
+        while let Some((hash, height)) = stack.pop() {
+            if let Some((prev, next)) = self.blocks.take_node(&hash) {
+                stack.extend(next.into_iter().map(|hash| (hash, height + 1)));
+                self.record_stale(StaleBlock {
+                    height,
+                    hash,
+                    prev,
+                    fork_point,
+                });
+            }
+        }
+    }
+
+    /// Whether [`Reorder::start_after`] was used and its block wasn't read yet
+    fn looking_for_start(&self) -> bool {
+        self.start_after.is_some() && self.ancestors_frontier.is_none()
+    }
+
+    /// Check if a block just read is known to be stale already, from its parent
+    fn arriving_stale(&self, hash: &BlockHash, prev: &BlockHash) -> Option<StaleBlock> {
+        let (height, fork_point) =
+            if let Some(&(prev_height, fork_point)) = self.stale_known.get(prev) {
+                (prev_height + 1, fork_point)
+            } else {
+                match self.main_chain.get(prev) {
+                    Some(&(prev_height, Some(next))) if next != *hash => (
+                        prev_height + 1,
+                        BlockHeightAndHash {
+                            height: prev_height,
+                            hash: *prev,
+                        },
+                    ),
+                    _ => return None,
+                }
+            };
+        Some(StaleBlock {
+            height,
+            hash: *hash,
+            prev: *prev,
+            fork_point,
+        })
+    }
+
+    /// Set the limit of blocks buffered while waiting for the next block of the chain
+    ///
+    /// Going over it makes the iterator fail with [`ReorderBufferFull`].
+    /// The default is [`DEFAULT_MAX_BUFFERED`].
+    pub fn max_buffered(mut self, max_buffered: usize) -> Self {
+        self.max_buffered = max_buffered;
+        self
+    }
+}
+
+impl<I, O> Reorder<I, O>
+where
+    O: FromFsBlock,
+{
+    /// Yield the next block of the chain being flushed at the end of input
+    fn flush_next(&mut self) -> Result<Option<O>> {
+        loop {
+            let path = self.flush_path.as_mut().expect("flushing");
+            let hash = if let Some(hash) = path.pop_front() {
+                hash
+            } else {
+                return Ok(None);
+            };
+            let next = path.front().copied();
+            let depth = path.len() as u32;
+
+            let mut stored_block = self.blocks.take(&hash).expect("buffered");
+            stored_block.next = next.into_iter().collect();
+            if let Some(next) = next {
+                self.next = next;
+            }
+            let height = self.height;
+            self.record_main_chain(height, hash, stored_block.prev, next);
+            self.height += 1;
+            if self.start_after == Some(hash) {
+                self.start_after = None;
+                continue;
+            }
+            self.last_unconfirmed_depth = Some(depth);
+            return Ok(Some(O::from_fs_block(stored_block, height)?));
+        }
+    }
+}
+
+impl<I, O> FallibleIterator for Reorder<I, O>
 where
     I: FallibleIterator<Item = FsBlock, Error = anyhow::Error>,
+    O: FromFsBlock,
 {
-    type Item = BlockExtra;
+    type Item = O;
     type Error = anyhow::Error;
 
     fn next(&mut self) -> Result<Option<Self::Item>> {
+        if self.flush_path.is_some() {
+            return self.flush_next();
+        }
+
         loop {
-            if let Some(stored_block) = self.blocks.remove(&self.next) {
-                let mut block_extra: BlockExtra = stored_block.try_into()?;
-                self.next = block_extra.next[0];
-                block_extra.height = self.height;
-                self.blocks.follows.remove(&block_extra.block_hash);
-                self.blocks
-                    .blocks
-                    .remove(&block_extra.block.header.prev_blockhash);
+            if let Some((stored_block, stale)) = self.blocks.remove(&self.next) {
+                let hash = stored_block.hash;
+                let prev = stored_block.prev;
+                let next = stored_block.next[0];
+                let height = self.height;
+                self.record_main_chain(height, hash, prev, Some(next));
+                self.remove_stale(stale, BlockHeightAndHash { height, hash });
+                self.last_unconfirmed_depth = None;
+                let item = if self.start_after == Some(hash) {
+                    self.start_after = None;
+                    None
+                } else {
+                    Some(O::from_fs_block(stored_block, self.height)?)
+                };
+                self.next = next;
+                self.blocks.follows.remove(&hash);
+                self.blocks.forget(&prev);
                 self.height += 1;
-                return Ok(Some(block_extra));
+                match item {
+                    Some(item) => return Ok(Some(item)),
+                    None => continue,
+                }
             }
 
             match self.iter.next() {
                 Ok(Some(raw_block)) => {
-                    // even tough should be 1024 -> https://github.com/bitcoin/bitcoin/search?q=BLOCK_DOWNLOAD_WINDOW
-                    // in practice it needs to be greater
-                    let max_block_to_reorder = 10_000;
-                    if self.blocks.blocks.len() > max_block_to_reorder {
-                        for block in self.blocks.blocks.values() {
-                            println!("{} {:?}", block.hash, block.next);
+                    let hash = raw_block.hash;
+                    let prev = raw_block.prev;
+                    if self.ancestors_frontier == Some(hash) {
+                        // already processed
+                        self.ancestors_frontier =
+                            Some(self.blocks.forget_with_ancestors(prev, hash));
+                        continue;
+                    }
+                    if self.blocks.forgotten.contains(&prev) {
+                        // forks off below the start block
+                        self.blocks.forgotten.insert(hash);
+                        continue;
+                    }
+                    if let Some(stale) = self.arriving_stale(&hash, &prev) {
+                        self.record_stale(stale);
+                        continue;
+                    }
+                    if !self.looking_for_start() && self.blocks.len() >= self.max_buffered {
+                        return Err(ReorderBufferFull {
+                            limit: self.max_buffered,
+                            next: self.next,
+                            largest_subtrees: self.blocks.largest_subtrees(REPORTED_SUBTREES),
                         }
-                        println!("next: {}", self.next);
-                        panic!("Reorder map grow more than {}", max_block_to_reorder);
+                        .into());
                     }
                     self.blocks.add(raw_block);
+                    if self.start_after == Some(hash) {
+                        self.ancestors_frontier =
+                            Some(self.blocks.forget_with_ancestors(prev, hash));
+                    }
                 }
                 Err(e) => return Err(e.into()),
                 Ok(None) => {
-                    return Ok(None);
+                    return match self.end_of_input {
+                        EndOfInput::Stop => Ok(None),
+                        EndOfInput::FlushTip => {
+                            self.flush_path = Some(self.blocks.best_path(&self.next));
+                            self.flush_next()
+                        }
+                    };
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{EndOfInput, Reorder, ReorderBufferFull, StaleBlock};
+    use crate::source::{BlkFile, FromFsBlock, FsBlock};
+    use anyhow::Result;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{BlockHash, Network};
+    use block_iter_core::{BlockHeight, BlockHeightAndHash};
+    use fallible_iterator::FallibleIterator;
+    use std::sync::Arc;
+
+    #[derive(Debug, PartialEq)]
+    struct HeightAndHash(BlockHeight, BlockHash);
+
+    impl FromFsBlock for HeightAndHash {
+        fn from_fs_block(fs_block: FsBlock, height: BlockHeight) -> Result<Self> {
+            Ok(HeightAndHash(height, fs_block.hash))
+        }
+    }
+
+    fn hash(height: u32) -> BlockHash {
+        BlockHash::hash(&height.to_le_bytes())
+    }
+
+    const REGTEST_BITS: u32 = 0x207fffff;
+    const MAINNET_BITS: u32 = 0x1d00ffff;
+
+    /// A fake block; only the links and the work matter
+    fn fs_block(file: &Arc<BlkFile>, id: u32, prev: Option<u32>, bits: u32) -> FsBlock {
+        FsBlock {
+            file: file.clone(),
+            start: 0,
+            end: 0,
+            hash: hash(id),
+            prev: prev.map(hash).unwrap_or_default(),
+            bits,
+            next: vec![],
+        }
+    }
+
+    /// Any file will do, it's never read
+    fn file() -> Arc<BlkFile> {
+        Arc::new(BlkFile::open(&std::env::current_exe().unwrap()).unwrap())
+    }
+
+    /// Fake blocks of a chain at `heights`
+    fn blocks(heights: &[u32]) -> Vec<FsBlock> {
+        let file = file();
+        heights
+            .iter()
+            .map(|&height| fs_block(&file, height, height.checked_sub(1), REGTEST_BITS))
+            .collect()
+    }
+
+    fn reorder_blocks(
+        blocks: Vec<FsBlock>,
+        max_reorg: u8,
+    ) -> Reorder<impl FallibleIterator<Item = FsBlock, Error = anyhow::Error>, HeightAndHash> {
+        Reorder::new(
+            Network::Regtest,
+            max_reorg,
+            fallible_iterator::convert(blocks.into_iter().map(Ok)),
+        )
+        .with_output()
+    }
+
+    fn reorder(
+        heights: &[u32],
+    ) -> Reorder<impl FallibleIterator<Item = FsBlock, Error = anyhow::Error>, HeightAndHash> {
+        reorder_blocks(blocks(heights), 2)
+    }
+
+    /// Blocks 0 and 1, with a fork of 2 blocks with `short_fork_bits` and a fork
+    /// of 3 blocks with `long_fork_bits` on top of it, both read before block 1
+    fn competing_forks(long_fork_bits: u32, short_fork_bits: u32) -> Vec<HeightAndHash> {
+        let file = file();
+        let blocks = vec![
+            fs_block(&file, 0, None, REGTEST_BITS),
+            fs_block(&file, 22, Some(1), short_fork_bits),
+            fs_block(&file, 23, Some(22), short_fork_bits),
+            fs_block(&file, 12, Some(1), long_fork_bits),
+            fs_block(&file, 13, Some(12), long_fork_bits),
+            fs_block(&file, 14, Some(13), long_fork_bits),
+            fs_block(&file, 1, Some(0), REGTEST_BITS),
+        ];
+        let mut reorder = reorder_blocks(blocks, 1)
+            .start_after(0, hash(0))
+            .end_of_input(EndOfInput::FlushTip);
+        let mut yielded = vec![];
+        while let Some(item) = reorder.next().unwrap() {
+            yielded.push(item);
+        }
+        yielded
+    }
+
+    #[test]
+    fn most_work_fork_wins() {
+        assert_eq!(
+            competing_forks(REGTEST_BITS, MAINNET_BITS),
+            vec![
+                HeightAndHash(1, hash(1)),
+                HeightAndHash(2, hash(22)),
+                HeightAndHash(3, hash(23)),
+            ]
+        );
+        assert_eq!(
+            competing_forks(REGTEST_BITS, REGTEST_BITS),
+            vec![
+                HeightAndHash(1, hash(1)),
+                HeightAndHash(2, hash(12)),
+                HeightAndHash(3, hash(13)),
+                HeightAndHash(4, hash(14)),
+            ]
+        );
+    }
+
+    #[test]
+    fn start_after_drops_ancestors() {
+        let mut reorder = reorder(&[0, 2, 3, 4, 6, 5, 1, 7, 8, 9, 10, 11]).start_after(5, hash(5));
+        let mut yielded = vec![];
+        while let Some(item) = reorder.next().unwrap() {
+            yielded.push(item);
+        }
+        assert_eq!(
+            yielded,
+            (6..=9)
+                .map(|h| HeightAndHash(h, hash(h)))
+                .collect::<Vec<_>>()
+        );
+        // only the blocks waiting for enough followers are left
+        assert_eq!(reorder.blocks.len(), 2);
+    }
+
+    #[test]
+    fn start_after_drops_stale_forks() {
+        let file = file();
+        let mut input = blocks(&[0, 1, 2, 3]);
+        // a fork off 3H read before the start block, extended after it
+        input.push(fs_block(&file, 13, Some(3), REGTEST_BITS));
+        input.extend(blocks(&[4]));
+        input.push(fs_block(&file, 14, Some(13), REGTEST_BITS));
+        input.extend(blocks(&[5, 6]));
+        input.push(fs_block(&file, 15, Some(14), REGTEST_BITS));
+        // a fork off 2H read only after the start block
+        input.push(fs_block(&file, 23, Some(2), REGTEST_BITS));
+        input.extend(blocks(&[7, 8, 9, 10, 11]));
+
+        let mut reorder = reorder_blocks(input, 2).start_after(5, hash(5));
+        let mut heights = vec![];
+        while let Some(HeightAndHash(height, _)) = reorder.next().unwrap() {
+            heights.push(height);
+        }
+        assert_eq!(heights, vec![6, 7, 8, 9]);
+        // only the blocks waiting for enough followers are left
+        assert_eq!(reorder.blocks.len(), 2);
+        assert!(reorder.take_stale_blocks().is_empty());
+    }
+
+    #[test]
+    fn start_after_ancestors_not_counted() {
+        let heights: Vec<_> = (0..10).collect();
+        let mut reorder = reorder(&heights).start_after(5, hash(5)).max_buffered(3);
+        let mut yielded = vec![];
+        while let Some(HeightAndHash(height, _)) = reorder.next().unwrap() {
+            yielded.push(height);
+        }
+        assert_eq!(yielded, vec![6, 7]);
+    }
+
+    #[test]
+    fn buffer_full() {
+        let mut reorder = reorder(&[0, 1, 3, 4, 5, 6])
+            .start_after(0, hash(0))
+            .max_buffered(5);
+        let err = reorder.next().unwrap_err();
+        let err = err.downcast_ref::<ReorderBufferFull>().unwrap();
+        assert_eq!(err.next, hash(0));
+        assert_eq!(err.largest_subtrees.len(), 2);
+        assert_eq!(err.largest_subtrees[0].root, hash(3));
+        assert_eq!(err.largest_subtrees[0].missing_prev, hash(2));
+        assert_eq!(err.largest_subtrees[0].size, 3);
+    }
+
+    #[test]
+    fn flush_tip() {
+        let mut reorder = reorder(&[0, 1, 2, 3, 4, 5])
+            .start_after(0, hash(0))
+            .end_of_input(EndOfInput::FlushTip);
+        let mut yielded = vec![];
+        while let Some(HeightAndHash(height, _)) = reorder.next().unwrap() {
+            yielded.push((height, reorder.last_unconfirmed_depth()));
+        }
+        assert_eq!(
+            yielded,
+            vec![(1, None), (2, None), (3, None), (4, Some(1)), (5, Some(0))]
+        );
+
+        let mut stopping = reorder(&[0, 1, 2, 3, 4, 5]).start_after(0, hash(0));
+        let mut heights = vec![];
+        while let Some(HeightAndHash(height, _)) = stopping.next().unwrap() {
+            heights.push(height);
+        }
+        assert_eq!(heights, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn stale_blocks() {
+        let file = file();
+        let mut input = blocks(&[0, 1, 2]);
+        input.push(fs_block(&file, 12, Some(1), REGTEST_BITS));
+        input.extend(blocks(&[3, 4, 5]));
+        // descendant of a stale block, and a late sibling of a yielded block
+        input.push(fs_block(&file, 13, Some(12), REGTEST_BITS));
+        input.push(fs_block(&file, 22, Some(2), REGTEST_BITS));
+
+        let mut reorder = reorder_blocks(input, 2)
+            .start_after(0, hash(0))
+            .record_tree(true);
+        let mut heights = vec![];
+        while let Some(HeightAndHash(height, _)) = reorder.next().unwrap() {
+            heights.push(height);
+        }
+        assert_eq!(heights, vec![1, 2, 3]);
+
+        let stale = |height, id, prev, fork_height| StaleBlock {
+            height,
+            hash: hash(id),
+            prev: hash(prev),
+            fork_point: BlockHeightAndHash {
+                height: fork_height,
+                hash: hash(fork_height),
+            },
+        };
+        assert_eq!(
+            reorder.take_stale_blocks(),
+            vec![stale(2, 12, 1, 1), stale(3, 13, 12, 1), stale(3, 22, 2, 2)]
+        );
+        assert!(reorder.take_stale_blocks().is_empty());
+
+        let tree = reorder.block_tree().unwrap();
+        assert_eq!(tree.stale_count(..), 3);
+        assert_eq!(tree.stale_count(3..), 2);
+        let dot = tree.to_dot(1..=2);
+        assert_eq!(dot.matches("color=red").count(), 1);
+        assert_eq!(dot.matches(" -> ").count(), 2);
+    }
+}
@@ -0,0 +1,78 @@
+use bitcoin::BlockHash;
+use block_iter_core::BlockHeight;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::ops::RangeBounds;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TreeBlock {
+    hash: BlockHash,
+    prev: BlockHash,
+    stale: bool,
+}
+
+/// The tree of blocks seen by [`super::Reorder`]: the yielded chain and stale blocks
+///
+/// Enabled with [`super::Reorder::record_tree`].
+#[derive(Debug, Clone, Default)]
+pub struct BlockTree {
+    blocks: BTreeMap<BlockHeight, Vec<TreeBlock>>,
+}
+
+impl BlockTree {
+    pub(super) fn insert(
+        &mut self,
+        height: BlockHeight,
+        hash: BlockHash,
+        prev: BlockHash,
+        stale: bool,
+    ) {
+        self.blocks
+            .entry(height)
+            .or_default()
+            .push(TreeBlock { hash, prev, stale });
+    }
+
+    /// Number of stale blocks at `heights`
+    pub fn stale_count(&self, heights: impl RangeBounds<BlockHeight>) -> usize {
+        self.blocks
+            .range(heights)
+            .flat_map(|(_, blocks)| blocks)
+            .filter(|block| block.stale)
+            .count()
+    }
+
+    /// Export the blocks at `heights` in the Graphviz DOT format
+    ///
+    /// Blocks point to their parents; stale ones are drawn in red.
+    pub fn to_dot(&self, heights: impl RangeBounds<BlockHeight>) -> String {
+        let blocks: Vec<_> = self
+            .blocks
+            .range(heights)
+            .flat_map(|(height, blocks)| blocks.iter().map(move |block| (*height, block)))
+            .collect();
+        let hashes: HashSet<_> = blocks.iter().map(|(_, block)| block.hash).collect();
+
+        let mut dot = String::from("digraph blocks {\n  rankdir=RL;\n");
+        for (height, block) in &blocks {
+            let hash = block.hash.to_string();
+            writeln!(
+                dot,
+                "  \"{}\" [label=\"{}H\\n..{}\"{}];",
+                hash,
+                height,
+                &hash[hash.len() - 12..],
+                if block.stale { " color=red" } else { "" }
+            )
+            .expect("writing to a String works");
+        }
+        for (_, block) in &blocks {
+            if hashes.contains(&block.prev) {
+                writeln!(dot, "  \"{}\" -> \"{}\";", block.hash, block.prev)
+                    .expect("writing to a String works");
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
@@ -0,0 +1,258 @@
+//! Random access to the blocks of a blocks dir
+
+use crate::source::format::{Bitcoin, BlockFormat};
+use crate::source::read_detect::ReadDetect;
+use crate::source::reorder::{EndOfInput, Reorder};
+use crate::source::{BlkFile, FsBlock};
+use anyhow::{bail, format_err, Result};
+use block_iter_core::{BlockHash, BlockHeight, BlockHeightAndHash, ChainParams, WithHeightAndId};
+use fallible_iterator::FallibleIterator;
+use log::info;
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// How many blk files are kept open, the most recently read ones
+const MAX_OPEN_FILES: usize = 16;
+
+/// Where a block is stored
+struct StoredBlock {
+    /// Index in `BlockStore::paths`
+    file: u32,
+    start: usize,
+    end: usize,
+    hash: BlockHash,
+}
+
+/// Index of the best chain in a blocks dir, reading blocks on demand
+///
+/// Only the position of every block is kept in memory, each lookup reads and
+/// decodes just the requested block. Files are opened on demand, and only the
+/// last few read are kept open. `F` is the encoding of the blocks.
+pub struct BlockStore<F = Bitcoin> {
+    /// Blocks by height
+    blocks: Vec<StoredBlock>,
+    heights: HashMap<BlockHash, BlockHeight>,
+    paths: Vec<Arc<Path>>,
+    file_ids: HashMap<Arc<Path>, u32>,
+    /// Most recently used first
+    open_files: Mutex<VecDeque<(u32, Arc<BlkFile>)>>,
+    _format: PhantomData<fn() -> F>,
+}
+
+impl BlockStore {
+    /// Index the blocks in `blocks_dir`, up to the tip of the most-work chain
+    ///
+    /// Only headers are read, with `ReadDetect` and `Reorder`.
+    pub fn from_blocks_dir(blocks_dir: &Path, params: impl Into<ChainParams>) -> Result<Self> {
+        let params = params.into();
+        let read_detect = ReadDetect::new(blocks_dir, params)?.headers_only(true);
+        let reorder = Reorder::new(params, params.max_reorg_hint, read_detect)
+            .end_of_input(EndOfInput::FlushTip)
+            .with_output::<WithHeightAndId<FsBlock>>();
+        let store = Self::from_reordered(reorder)?;
+        info!("Indexed {} blocks of {:?}", store.blocks.len(), blocks_dir);
+        Ok(store)
+    }
+}
+
+impl<F: BlockFormat> BlockStore<F> {
+    pub fn new() -> Self {
+        Self {
+            blocks: vec![],
+            heights: HashMap::new(),
+            paths: vec![],
+            file_ids: HashMap::new(),
+            open_files: Mutex::new(VecDeque::new()),
+            _format: PhantomData,
+        }
+    }
+
+    /// Index the blocks yielded by a `Reorder`, starting at the genesis block
+    ///
+    /// Use `Reorder::with_output::<WithHeightAndId<FsBlock>>()`.
+    pub fn from_reordered<I>(mut iter: I) -> Result<Self>
+    where
+        I: FallibleIterator<Item = WithHeightAndId<FsBlock>, Error = anyhow::Error>,
+    {
+        let mut store = Self::new();
+        while let Some(block) = iter.next()? {
+            store.push(block)?;
+        }
+        Ok(store)
+    }
+
+    /// Add the block following the current tip
+    ///
+    /// Only its position is kept, its file is closed once not used elsewhere.
+    pub fn push(&mut self, block: WithHeightAndId<FsBlock>) -> Result<()> {
+        if block.height as usize != self.blocks.len() {
+            bail!(
+                "Block {} at height {} doesn't follow the tip at {:?}",
+                block.id,
+                block.height,
+                self.tip()
+            );
+        }
+        let path = block.data.file.path();
+        let file = match self.file_ids.get(path) {
+            Some(file) => *file,
+            None => {
+                let path: Arc<Path> = Arc::from(path);
+                self.paths.push(Arc::clone(&path));
+                let file = (self.paths.len() - 1) as u32;
+                self.file_ids.insert(path, file);
+                file
+            }
+        };
+        self.heights.insert(block.id, block.height);
+        self.blocks.push(StoredBlock {
+            file,
+            start: block.data.start,
+            end: block.data.end,
+            hash: block.id,
+        });
+        Ok(())
+    }
+
+    pub fn tip(&self) -> Option<BlockHeightAndHash> {
+        self.blocks.last().map(|block| BlockHeightAndHash {
+            height: self.blocks.len() as BlockHeight - 1,
+            hash: block.hash,
+        })
+    }
+
+    pub fn height_of(&self, hash: &BlockHash) -> Option<BlockHeight> {
+        self.heights.get(hash).copied()
+    }
+
+    /// Read `len` bytes of the block at `height`, from `offset` since its start
+    pub fn read_range(
+        &self,
+        height: BlockHeight,
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        let block = match self.blocks.get(height as usize) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let file = self.file(block.file)?;
+        Ok(Some(file.read_block_range(
+            &block.hash,
+            (block.start, block.end),
+            offset,
+            len,
+        )?))
+    }
+
+    /// The file with index `id`, opening it if it's not among the recently used ones
+    fn file(&self, id: u32) -> Result<Arc<BlkFile>> {
+        let mut open_files = self
+            .open_files
+            .lock()
+            .map_err(|_e| format_err!("locking failed"))?;
+        if let Some(i) = open_files.iter().position(|(open_id, _)| *open_id == id) {
+            let entry = open_files.remove(i).expect("found");
+            let file = Arc::clone(&entry.1);
+            open_files.push_front(entry);
+            return Ok(file);
+        }
+        let file = Arc::new(BlkFile::open(&self.paths[id as usize])?);
+        open_files.push_front((id, Arc::clone(&file)));
+        open_files.truncate(MAX_OPEN_FILES);
+        Ok(file)
+    }
+
+    pub fn get_by_height(&self, height: BlockHeight) -> Result<Option<WithHeightAndId<F::Block>>> {
+        let block = match self.blocks.get(height as usize) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let bytes = self
+            .read_range(height, 0, block.end - block.start)?
+            .expect("block exists");
+        Ok(Some(WithHeightAndId {
+            height,
+            id: block.hash,
+            data: F::decode_block(&bytes[..])?,
+        }))
+    }
+
+    pub fn get_by_hash(&self, hash: &BlockHash) -> Result<Option<WithHeightAndId<F::Block>>> {
+        match self.height_of(hash) {
+            Some(height) => self.get_by_height(height),
+            None => Ok(None),
+        }
+    }
+
+    /// Blocks with heights in `range`, read one by one while iterating
+    pub fn range(
+        &self,
+        range: Range<BlockHeight>,
+    ) -> impl Iterator<Item = Result<WithHeightAndId<F::Block>>> + '_ {
+        let end = range.end.min(self.blocks.len() as BlockHeight);
+        (range.start..end)
+            .map(move |height| Ok(self.get_by_height(height)?.expect("below the tip")))
+    }
+}
+
+impl<F: BlockFormat> Default for BlockStore<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BlockStore, MAX_OPEN_FILES};
+    use crate::test_util::{regtest_chain, write_blk, TempDir};
+    use bitcoin::Network;
+
+    #[test]
+    fn random_access() {
+        let dir = TempDir::new();
+        let chain = regtest_chain(3);
+        let hashes: Vec<_> = chain.iter().map(|block| block.block_hash()).collect();
+        write_blk(&dir, 0, &chain);
+
+        let store = BlockStore::from_blocks_dir(&dir, Network::Regtest).unwrap();
+        assert_eq!(store.tip().unwrap().height, 2);
+        assert_eq!(store.tip().unwrap().hash, hashes[2]);
+        let block = store.get_by_height(1).unwrap().unwrap();
+        assert_eq!(block.data.block_hash(), hashes[1]);
+        let block = store.get_by_hash(&hashes[2]).unwrap().unwrap();
+        assert_eq!(block.height, 2);
+        assert!(store.get_by_height(3).unwrap().is_none());
+        let range: Vec<_> = store.range(1..10).map(|b| b.unwrap().id).collect();
+        assert_eq!(range, hashes[1..].to_vec());
+    }
+
+    #[test]
+    fn bounded_open_files() {
+        let dir = TempDir::new();
+
+        // one block per file
+        let files = MAX_OPEN_FILES + 4;
+        let chain = regtest_chain(files);
+        let hashes: Vec<_> = chain.iter().map(|block| block.block_hash()).collect();
+        for (i, block) in chain.chunks(1).enumerate() {
+            write_blk(&dir, i, block);
+        }
+
+        let store = BlockStore::from_blocks_dir(&dir, Network::Regtest).unwrap();
+        assert_eq!(store.open_files.lock().unwrap().len(), 0);
+        let range: Vec<_> = store
+            .range(0..files as u32)
+            .map(|b| b.unwrap().id)
+            .collect();
+        assert_eq!(range, hashes);
+        assert_eq!(store.open_files.lock().unwrap().len(), MAX_OPEN_FILES);
+        // evicted, opened again
+        let block = store.get_by_height(0).unwrap().unwrap();
+        assert_eq!(block.id, hashes[0]);
+        assert_eq!(store.open_files.lock().unwrap()[0].0, 0);
+    }
+}
@@ -0,0 +1,72 @@
+//! Helpers for tests reading blk files
+
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::serialize;
+use bitcoin::{Block, Network};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A new empty directory, removed with everything in it when dropped,
+/// also when the test panics
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "block-iter-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        // left over by a process with the same id
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A blk file record: `magic`, declared `size`, and the `bytes` actually written
+pub(crate) fn record(magic: u32, size: u32, bytes: &[u8]) -> Vec<u8> {
+    let mut record = magic.to_le_bytes().to_vec();
+    record.extend(size.to_le_bytes());
+    record.extend(bytes);
+    record
+}
+
+/// A complete blk file record of a regtest block
+pub(crate) fn block_record(block: &Block) -> Vec<u8> {
+    let bytes = serialize(block);
+    record(Network::Regtest.magic(), bytes.len() as u32, &bytes)
+}
+
+/// Regtest chain of `len` blocks, starting with the genesis block
+pub(crate) fn regtest_chain(len: usize) -> Vec<Block> {
+    let mut block = genesis_block(Network::Regtest);
+    let mut chain = vec![];
+    for _ in 0..len {
+        chain.push(block.clone());
+        block.header.prev_blockhash = block.block_hash();
+    }
+    chain
+}
+
+/// Write `blocks` to the blk file number `index` in `dir`
+pub(crate) fn write_blk(dir: &Path, index: usize, blocks: &[Block]) {
+    let file: Vec<u8> = blocks.iter().flat_map(block_record).collect();
+    std::fs::write(dir.join(format!("blk{:05}.dat", index)), file).unwrap();
+}
@@ -0,0 +1,181 @@
+//! Index of the transactions by txid, stored with `sled`
+
+use crate::source::block_extra::BlockExtra;
+use crate::store::BlockStore;
+use anyhow::{bail, format_err, Result};
+use bitcoin::consensus::encode;
+use bitcoin::{Block, Transaction, Txid, VarInt};
+use block_iter_core::BlockHeight;
+use fallible_iterator::FallibleIterator;
+use std::convert::TryInto;
+use std::path::Path;
+
+/// Size of a value: height, index in the block, offset in the block, size
+const VALUE_LEN: usize = 4 + 4 + 4 + 4;
+
+/// Where a transaction is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxPosition {
+    pub height: BlockHeight,
+    /// Index of the transaction in the block
+    pub index: u32,
+    /// Offset in bytes of the transaction since the start of the block
+    pub offset: u32,
+    /// Size in bytes of the encoded transaction
+    pub size: u32,
+}
+
+impl TxPosition {
+    fn to_bytes(self) -> [u8; VALUE_LEN] {
+        let mut bytes = [0u8; VALUE_LEN];
+        bytes[0..4].copy_from_slice(&self.height.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.index.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.size.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != VALUE_LEN {
+            bail!("Invalid txindex value of {} bytes", bytes.len());
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Ok(Self {
+            height: u32_at(0),
+            index: u32_at(4),
+            offset: u32_at(8),
+            size: u32_at(12),
+        })
+    }
+}
+
+/// Txid to [`TxPosition`] index
+///
+/// Filled while iterating blocks in the chain order, with [`TxIndex::index`] or
+/// [`TxIndex::insert`]. Positions are relative to the blocks, so transactions are
+/// read back through a [`BlockStore`] of the same chain. Like in Bitcoin Core,
+/// a later transaction with a duplicate txid replaces the earlier one.
+#[derive(Clone)]
+pub struct TxIndex {
+    db: sled::Db,
+}
+
+impl TxIndex {
+    /// Open the index at `path`, creating it if missing
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            db: sled::open(path)?,
+        })
+    }
+
+    /// Record the positions of the transactions in `block`
+    pub fn insert(&self, height: BlockHeight, block: &Block) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        // header, then the number of transactions
+        let mut offset = 80 + VarInt(block.txdata.len() as u64).len();
+        for (index, tx) in block.txdata.iter().enumerate() {
+            let size = tx.get_size();
+            let position = TxPosition {
+                height,
+                index: index as u32,
+                offset: offset as u32,
+                size: size as u32,
+            };
+            batch.insert(&tx.txid()[..], &position.to_bytes()[..]);
+            offset += size;
+        }
+        self.db.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// Index the blocks passing through
+    pub fn index<I>(
+        &self,
+        iter: I,
+    ) -> impl FallibleIterator<Item = BlockExtra, Error = anyhow::Error>
+    where
+        I: FallibleIterator<Item = BlockExtra, Error = anyhow::Error>,
+    {
+        let index = self.clone();
+        iter.map(move |block| {
+            index.insert(block.height, &block.block)?;
+            Ok(block)
+        })
+    }
+
+    pub fn get(&self, txid: &Txid) -> Result<Option<TxPosition>> {
+        match self.db.get(&txid[..])? {
+            Some(value) => Ok(Some(TxPosition::from_bytes(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read and decode only the transaction with `txid` from the blk files of `store`
+    pub fn get_transaction(&self, store: &BlockStore, txid: &Txid) -> Result<Option<Transaction>> {
+        let position = match self.get(txid)? {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+        let bytes = store
+            .read_range(
+                position.height,
+                position.offset as usize,
+                position.size as usize,
+            )?
+            .ok_or_else(|| {
+                format_err!(
+                    "Transaction {} at height {} is past the tip of the store",
+                    txid,
+                    position.height
+                )
+            })?;
+        let tx: Transaction = encode::deserialize(&bytes)?;
+        if &tx.txid() != txid {
+            bail!(
+                "Transaction {} at height {} is {} in the store, is it of another chain?",
+                txid,
+                position.height,
+                tx.txid()
+            );
+        }
+        Ok(Some(tx))
+    }
+
+    /// Write everything to disk
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TxIndex;
+    use crate::store::BlockStore;
+    use crate::test_util::{regtest_chain, write_blk, TempDir};
+    use bitcoin::{Network, Txid};
+
+    #[test]
+    fn get_transaction() {
+        let dir = TempDir::new();
+        let chain = regtest_chain(1);
+        let genesis = &chain[0];
+        write_blk(&dir, 0, &chain);
+
+        let store = BlockStore::from_blocks_dir(&dir, Network::Regtest).unwrap();
+        let index = TxIndex::open(&dir.join("txindex")).unwrap();
+        index.insert(0, genesis).unwrap();
+
+        let coinbase = &genesis.txdata[0];
+        let position = index.get(&coinbase.txid()).unwrap().unwrap();
+        assert_eq!(position.offset, 81);
+        assert_eq!(
+            index.get_transaction(&store, &coinbase.txid()).unwrap(),
+            Some(coinbase.clone())
+        );
+        assert_eq!(
+            index.get_transaction(&store, &Txid::default()).unwrap(),
+            None
+        );
+    }
+}
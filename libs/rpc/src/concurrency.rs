@@ -0,0 +1,245 @@
+use log::debug;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Number of finished requests between adjustments of the limit
+pub(crate) const WINDOW_LEN: u32 = 20;
+
+/// Error rate above which the limit is decreased
+const MAX_ERROR_RATE: f64 = 0.1;
+
+/// How many times can the latency grow above the baseline before the limit is decreased
+const MAX_LATENCY_FACTOR: u32 = 2;
+
+/// Snapshot of the state of the adaptive concurrency control of a `Fetcher`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConcurrencyStats {
+    /// Current limit of requests in flight
+    pub limit: usize,
+    /// Number of requests in flight right now
+    pub in_flight: usize,
+    /// Blocks per second, measured over the last adjustment window
+    pub throughput: f64,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    successes: u32,
+    errors: u32,
+    latency_total: Duration,
+}
+
+impl Window {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            successes: 0,
+            errors: 0,
+            latency_total: Duration::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    window: Window,
+    /// Typical latency of a healthy node
+    baseline_latency: Option<Duration>,
+    throughput: f64,
+}
+
+/// Adaptive limit of requests in flight
+///
+/// Adjusted with AIMD (additive increase, multiplicative decrease): after
+/// every `WINDOW_LEN` requests, the limit is halved if the error rate or
+/// latency went up, and increased by one otherwise.
+#[derive(Debug)]
+pub(crate) struct Concurrency {
+    min: usize,
+    max: usize,
+    limit: AtomicUsize,
+    in_flight: AtomicUsize,
+    state: Mutex<State>,
+}
+
+impl Concurrency {
+    /// Limit between `min` and `max`, starting at `initial`
+    pub(crate) fn new(min: usize, max: usize, initial: usize) -> Self {
+        assert!(0 < min && min <= initial && initial <= max);
+        Self {
+            min,
+            max,
+            limit: AtomicUsize::new(initial),
+            in_flight: AtomicUsize::new(0),
+            state: Mutex::new(State {
+                window: Window::new(),
+                baseline_latency: None,
+                throughput: 0.0,
+            }),
+        }
+    }
+
+    /// Get a permit to make a request, if below the limit
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let limit = self.limit.load(Ordering::SeqCst);
+        let mut cur = self.in_flight.load(Ordering::SeqCst);
+        loop {
+            if limit <= cur {
+                return None;
+            }
+            match self
+                .in_flight
+                .compare_exchange(cur, cur + 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return Some(Permit(self.clone())),
+                Err(actual) => cur = actual,
+            }
+        }
+    }
+
+    pub(crate) fn record_success(&self, latency: Duration) {
+        let mut state = self.state.lock().expect("lock works");
+        state.window.successes += 1;
+        state.window.latency_total += latency;
+        self.maybe_adjust(&mut state);
+    }
+
+    pub(crate) fn record_error(&self) {
+        let mut state = self.state.lock().expect("lock works");
+        state.window.errors += 1;
+        self.maybe_adjust(&mut state);
+    }
+
+    fn maybe_adjust(&self, state: &mut State) {
+        let total = state.window.successes + state.window.errors;
+        if total < WINDOW_LEN {
+            return;
+        }
+
+        let error_rate = f64::from(state.window.errors) / f64::from(total);
+        let elapsed = state.window.start.elapsed();
+        state.throughput = f64::from(state.window.successes) / elapsed.as_secs_f64().max(0.001);
+
+        let latency = state
+            .window
+            .latency_total
+            .checked_div(state.window.successes);
+        let baseline = state.baseline_latency;
+
+        let overloaded = MAX_ERROR_RATE < error_rate
+            || matches!(
+                (latency, baseline),
+                (Some(latency), Some(baseline)) if baseline * MAX_LATENCY_FACTOR < latency
+            );
+
+        if let Some(latency) = latency {
+            // follow the minimum quickly, and slowly drift towards higher latencies,
+            // e.g. when blocks are getting bigger
+            state.baseline_latency = Some(match baseline {
+                Some(baseline) if baseline < latency => baseline + (latency - baseline) / 10,
+                _ => latency,
+            });
+        }
+
+        let limit = self.limit.load(Ordering::SeqCst);
+        let new_limit = if overloaded {
+            (limit / 2).max(self.min)
+        } else {
+            (limit + 1).min(self.max)
+        };
+        if new_limit != limit {
+            debug!(
+                "Fetcher concurrency: {} -> {} (error rate: {:.2}, latency: {:?}, baseline: {:?})",
+                limit, new_limit, error_rate, latency, baseline
+            );
+            self.limit.store(new_limit, Ordering::SeqCst);
+        }
+
+        state.window = Window::new();
+    }
+
+    pub(crate) fn stats(&self) -> ConcurrencyStats {
+        ConcurrencyStats {
+            limit: self.limit.load(Ordering::SeqCst),
+            in_flight: self.in_flight.load(Ordering::SeqCst),
+            throughput: self.state.lock().expect("lock works").throughput,
+        }
+    }
+}
+
+/// Permit to have one request in flight, released on drop
+pub(crate) struct Permit(Arc<Concurrency>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Concurrency, WINDOW_LEN};
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn aimd() {
+        let c = Arc::new(Concurrency::new(1, 8, 1));
+        assert_eq!(c.stats().limit, 1);
+
+        let permit = c.try_acquire().unwrap();
+        assert!(c.try_acquire().is_none());
+        drop(permit);
+
+        for _ in 0..(WINDOW_LEN * 3) {
+            c.record_success(Duration::from_millis(10));
+        }
+        assert_eq!(c.stats().limit, 4);
+
+        // latency goes way up
+        for _ in 0..WINDOW_LEN {
+            c.record_success(Duration::from_millis(100));
+        }
+        assert_eq!(c.stats().limit, 2);
+
+        // errors
+        for _ in 0..WINDOW_LEN {
+            c.record_error();
+        }
+        assert_eq!(c.stats().limit, 1);
+    }
+
+    #[test]
+    fn latency_baseline() {
+        let c = Concurrency::new(1, 8, 4);
+        let window = |errors, latency_ms| {
+            for _ in 0..errors {
+                c.record_error();
+            }
+            for _ in errors..WINDOW_LEN {
+                c.record_success(Duration::from_millis(latency_ms));
+            }
+        };
+
+        // no baseline yet
+        window(0, 10);
+        assert_eq!(c.stats().limit, 5);
+        // slower, but not twice as slow as the 10ms baseline
+        window(0, 18);
+        assert_eq!(c.stats().limit, 6);
+        // baseline drifted only to 10.8ms
+        window(0, 25);
+        assert_eq!(c.stats().limit, 3);
+
+        // some errors are tolerated
+        window(WINDOW_LEN / 10, 10);
+        assert_eq!(c.stats().limit, 4);
+        window(WINDOW_LEN / 10 + 1, 10);
+        assert_eq!(c.stats().limit, 2);
+    }
+}
@@ -1,36 +1,87 @@
-use crate::Rpc;
-use anyhow::Result;
-use block_iter_core::{BlockHash, BlockHeight, WithHeightAndId, WithPrevBlockHash};
-use log::{debug, info, trace};
+use crate::{
+    concurrency::{Concurrency, Permit},
+    headers::{HeaderChain, HeaderSync, SharedHeaderChain},
+    ConcurrencyStats, Rpc, ShutdownHandle,
+};
+use anyhow::{bail, format_err, Result};
+use block_iter_core::{
+    BlockHash, BlockHeight, BlockHeightAndHash, ChainLocator, WithHeightAndId, WithPrevBlockHash,
+};
+use log::{debug, error, info, trace, warn};
 use std::{
     collections::{BTreeMap, BTreeSet, HashMap},
     sync::{
-        atomic::{AtomicBool, AtomicUsize, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
     },
-    time::Duration,
+    task::Poll,
+    time::{Duration, Instant},
 };
 
+/// How long to wait for workers to finish before detaching them
+const STOP_WORKERS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often do workers waiting for a concurrency permit check for it
+const PERMIT_POLL_DELAY: Duration = Duration::from_millis(10);
+
 /// Retry a failing rpc
-fn retry<T>(mut f: impl FnMut() -> Result<T>) -> T {
+///
+/// Returns `None` if shutdown was requested in the meantime.
+fn retry<T>(shutdown: &ShutdownHandle, mut f: impl FnMut() -> Result<T>) -> Option<T> {
     let delay_ms = 100;
     let mut count = 0;
     loop {
         match f() {
             Err(e) => {
-                std::thread::sleep(Duration::from_millis(delay_ms));
+                if shutdown.sleep(Duration::from_millis(delay_ms)) {
+                    return None;
+                }
                 if count % 1000 == 0 {
                     eprintln!("{}; retrying ...", e);
                 }
                 count += 1;
             }
             Ok(t) => {
-                return t;
+                return Some(t);
+            }
+        }
+    }
+}
+
+/// How many times a failing rpc is retried while creating a [`Fetcher`]
+const START_RETRIES: u32 = 5;
+
+/// Retry a failing rpc made while creating a [`Fetcher`]
+///
+/// There's no [`ShutdownHandle`] to stop it yet, so it gives up after
+/// [`START_RETRIES`] attempts, backing off exponentially, and returns
+/// the last error.
+fn retry_on_start<T>(mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = Duration::from_millis(100);
+    let mut count = 0;
+    loop {
+        match f() {
+            Err(e) if count < START_RETRIES => {
+                debug!("{}; retrying in {:?} ...", e, delay);
+                std::thread::sleep(delay);
+                delay *= 2;
+                count += 1;
             }
+            res => return res,
         }
     }
 }
 
+/// A reorg detected by the [`Fetcher`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reorg {
+    /// The last block that is still part of the node's best chain
+    pub fork_point: BlockHeightAndHash,
+    /// Blocks that were previously returned, and are no longer part of the
+    /// best chain, in ascending height order
+    pub disconnected: Vec<BlockHeightAndHash>,
+}
+
 /// A block fetcher from a `Rpc`
 ///
 /// Implemented as an iterator that yields block events in order,
@@ -48,6 +99,38 @@ fn retry<T>(mut f: impl FnMut() -> Result<T>) -> T {
 /// ```norust
 /// 1, 2, 3, 4, ..., 2, 3, 4 ...
 /// ```
+///
+/// Details of the last reorg (fork point and all the disconnected blocks)
+/// are available via [`Fetcher::take_reorg`].
+///
+/// Use [`Fetcher::shutdown_handle`] to stop it from another thread: sleeping
+/// workers are woken up, and `next` returns `None` promptly.
+///
+/// # Headers-first mode
+///
+/// By default blocks are fetched by height, so during a reorg the workers
+/// can end up fetching blocks from two different chains, which is detected
+/// only when the blocks are returned. With [`Fetcher::headers_first`]
+/// a separate thread keeps pulling the header chain, verifying that each
+/// header links to the previous one, and the workers download bodies
+/// by hashes from it. Every body belongs to one known chain, and reorgs
+/// are caught already when syncing headers.
+/// Workers waiting for headers are woken up as soon as they arrive.
+///
+/// # Concurrency
+///
+/// The number of requests in flight starts at the upper bound set with
+/// [`Fetcher::concurrency`], and is adjusted based on the measured latency
+/// and error rate of the node, backing off when it can't keep up.
+/// See [`Fetcher::concurrency_stats`].
+///
+/// # Pruned nodes
+///
+/// Creating a `Fetcher` fails if the node already pruned the first block
+/// to fetch. If the node prunes blocks before the `Fetcher` gets to them
+/// (eg. when the consumer is too slow), the `Fetcher` shuts down, and
+/// the reason is available via [`Fetcher::take_error`].
+///
 /// # Architecture notes
 ///
 /// Note that prefetcher does not have any access to the DB or
@@ -71,10 +154,27 @@ where
 
     cur_height: BlockHeight,
     prev_hashes: BTreeMap<BlockHeight, BlockHash>,
-    workers_finish: Arc<AtomicBool>,
+    /// Stops the current set of workers (eg. to restart them on reorg)
+    workers_stop: ShutdownHandle,
+    /// Stops everything, for good
+    shutdown: ShutdownHandle,
+    /// Fatal error that caused the shutdown
+    error: Arc<Mutex<Option<anyhow::Error>>>,
     thread_num: usize,
+    concurrency: Arc<Concurrency>,
     rpc: Arc<R>,
     end_of_fast_sync: BlockHeight,
+    /// Reorg detected since the last `take_reorg`
+    reorg: Option<Reorg>,
+    /// Workers are started lazily, on the first `next`
+    started: bool,
+    headers_first: bool,
+    /// Header chain, in headers-first mode
+    header_chain: Option<SharedHeaderChain>,
+    /// Notifications of reorgs in the header chain, in headers-first mode
+    header_reorg_rx: Option<crossbeam_channel::Receiver<()>>,
+    /// Stops the header sync thread
+    header_sync_stop: ShutdownHandle,
 }
 
 impl<R> Fetcher<R>
@@ -82,15 +182,145 @@ where
     R: Rpc + 'static,
     R::Data: WithPrevBlockHash,
 {
+    /// Create a `Fetcher` returning blocks right after `last_block`
+    ///
+    /// Fails if the node keeps returning errors, after a few retries.
     pub fn new(rpc: Arc<R>, last_block: Option<WithHeightAndId<R::Data>>) -> Result<Self> {
-        let thread_num = 8;
-        let workers_finish = Arc::new(AtomicBool::new(false));
+        let mut prev_hashes = BTreeMap::default();
+        if let Some(h_and_hash) = last_block {
+            prev_hashes.insert(h_and_hash.height, h_and_hash.id);
+        }
 
-        let end_of_fast_sync = retry(|| rpc.get_block_count());
+        Self::new_with_prev_hashes(rpc, prev_hashes, None)
+    }
+
+    /// Create a `Fetcher` resuming from a previously exported [`ChainLocator`]
+    ///
+    /// The fork point between the locator and the node's best chain is
+    /// found first, so any reorg that happened while we were not running is
+    /// detected and available via [`Fetcher::take_reorg`] before the first
+    /// block is returned. The locator has only some of the disconnected
+    /// blocks, so the rest of them, and the exact fork point, are found by
+    /// walking the stale chain through the headers the node still keeps.
+    /// Blocks are returned starting right above the fork point.
+    ///
+    /// Like [`Fetcher::new`], fails if the node keeps returning errors.
+    pub fn from_locator(rpc: Arc<R>, locator: &ChainLocator) -> Result<Self> {
+        let mut disconnected = vec![];
         let mut prev_hashes = BTreeMap::default();
-        let start = if let Some(h_and_hash) = last_block {
-            let h = h_and_hash.height;
-            prev_hashes.insert(h, h_and_hash.id);
+
+        for entry in locator.entries() {
+            if !prev_hashes.is_empty() {
+                prev_hashes.insert(entry.height, entry.hash);
+                continue;
+            }
+            let node_hash = retry_on_start(|| rpc.get_block_id_by_height(entry.height))?;
+            if node_hash == Some(entry.hash) {
+                prev_hashes.insert(entry.height, entry.hash);
+            } else {
+                disconnected.push(*entry);
+            }
+        }
+
+        if !locator.is_empty() && prev_hashes.is_empty() {
+            bail!("No block of the chain locator is in the node's best chain. Wrong network?");
+        }
+
+        let reorg = if disconnected.is_empty() {
+            None
+        } else {
+            let last_common = prev_hashes
+                .iter()
+                .next_back()
+                .map(|(&height, &hash)| BlockHeightAndHash { height, hash })
+                .expect("not empty");
+            let (fork_point, disconnected) =
+                Self::walk_stale_chain(&rpc, &disconnected, last_common)?;
+            prev_hashes.insert(fork_point.height, fork_point.hash);
+            warn!(
+                "Reorg detected on start at {}H {}; {} blocks disconnected",
+                fork_point.height,
+                fork_point.hash,
+                disconnected.len()
+            );
+            Some(Reorg {
+                fork_point,
+                disconnected,
+            })
+        };
+
+        Self::new_with_prev_hashes(rpc, prev_hashes, reorg)
+    }
+
+    /// Find the exact fork point of a stale chain known only by a few entries
+    ///
+    /// `entries` are the locator entries not in the best chain, in descending
+    /// height order, and `last_common` is the highest one that still is.
+    /// The stale chain is walked down from its highest entry, until it meets
+    /// the best chain. Returns the fork point and all the disconnected blocks,
+    /// in ascending height order.
+    fn walk_stale_chain(
+        rpc: &R,
+        entries: &[BlockHeightAndHash],
+        last_common: BlockHeightAndHash,
+    ) -> Result<(BlockHeightAndHash, Vec<BlockHeightAndHash>)> {
+        // every stale block above the lowest disconnected entry builds on it,
+        // so only the blocks below it need to be checked against the best chain
+        let lowest_entry = entries.last().expect("not empty").height;
+        let mut disconnected = vec![];
+        let mut cur = entries[0];
+
+        let fork_point = loop {
+            disconnected.push(cur);
+            let header =
+                retry_on_start(|| rpc.get_block_header_by_id(&cur.hash))?.ok_or_else(|| {
+                    format_err!(
+                        "Can't find the fork point: the node doesn't know the stale block {}H {}",
+                        cur.height,
+                        cur.hash
+                    )
+                })?;
+            let prev = BlockHeightAndHash {
+                height: cur.height - 1,
+                hash: header.prev_blockhash,
+            };
+
+            if prev.height == last_common.height {
+                if prev.hash != last_common.hash {
+                    bail!(
+                        "Inconsistent chain locator: {}H {} doesn't build on {}H {}",
+                        cur.height,
+                        cur.hash,
+                        last_common.height,
+                        last_common.hash
+                    );
+                }
+                break prev;
+            }
+            if prev.height < lowest_entry {
+                let node_hash = retry_on_start(|| rpc.get_block_id_by_height(prev.height))?;
+                if node_hash == Some(prev.hash) {
+                    break prev;
+                }
+            }
+            cur = prev;
+        };
+
+        disconnected.reverse();
+        Ok((fork_point, disconnected))
+    }
+
+    fn new_with_prev_hashes(
+        rpc: Arc<R>,
+        prev_hashes: BTreeMap<BlockHeight, BlockHash>,
+        reorg: Option<Reorg>,
+    ) -> Result<Self> {
+        let thread_num = 8;
+        // start at full speed, and back off only if the node can't keep up
+        let concurrency = Arc::new(Concurrency::new(1, thread_num, thread_num));
+
+        let end_of_fast_sync = retry_on_start(|| rpc.get_block_count())?;
+        let start = if let Some((h, _)) = prev_hashes.iter().next_back() {
             info!("Starting block fetcher starting at {}H", h + 1);
             h + 1
         } else {
@@ -98,24 +328,88 @@ where
             0
         };
 
-        let mut s = Self {
+        let prune_height = retry_on_start(|| rpc.get_prune_height())?;
+        if let Some(prune_height) = prune_height {
+            if start < prune_height {
+                bail!(
+                    "Can't start fetching at {}H: the node is pruned, and has blocks only since {}H",
+                    start,
+                    prune_height
+                );
+            }
+        }
+
+        Ok(Self {
             rx: None,
             rpc,
             thread_joins: Default::default(),
             thread_num,
+            concurrency,
             cur_height: start,
             out_of_order_items: Default::default(),
-            workers_finish,
+            workers_stop: ShutdownHandle::new(),
+            shutdown: ShutdownHandle::new(),
+            error: Default::default(),
             prev_hashes,
             end_of_fast_sync,
-        };
+            reorg,
+            started: false,
+            headers_first: false,
+            header_chain: None,
+            header_reorg_rx: None,
+            header_sync_stop: ShutdownHandle::new(),
+        })
+    }
+
+    /// Enable headers-first mode
+    ///
+    /// See [`Fetcher`] for details.
+    pub fn headers_first(mut self, enabled: bool) -> Self {
+        assert!(!self.started, "must be called before fetching any blocks");
+        self.headers_first = enabled;
+        self
+    }
+
+    /// Set bounds of the number of requests in flight
+    ///
+    /// The default is between 1 and 8. Fetching starts at `max`.
+    pub fn concurrency(mut self, min: usize, max: usize) -> Self {
+        assert!(!self.started, "must be called before fetching any blocks");
+        self.thread_num = max;
+        self.concurrency = Arc::new(Concurrency::new(min, max, max));
+        self
+    }
+
+    fn start(&mut self) {
+        if self.headers_first {
+            let header_chain = Arc::new(RwLock::new(HeaderChain::new(self.last_returned())));
+            self.header_chain = Some(header_chain.clone());
+            let (reorg_tx, reorg_rx) = crossbeam_channel::bounded(1);
+            self.header_reorg_rx = Some(reorg_rx);
+            std::thread::spawn({
+                let header_sync = HeaderSync {
+                    rpc: self.rpc.clone(),
+                    chain: header_chain,
+                    reorg_tx,
+                    stop: self.header_sync_stop.clone(),
+                    shutdown: self.shutdown.clone(),
+                };
+                move || header_sync.run()
+            });
+        }
+        self.start_workers();
+        self.started = true;
+    }
 
-        s.start_workers();
-        Ok(s)
+    fn last_returned(&self) -> Option<BlockHeightAndHash> {
+        self.prev_hashes
+            .iter()
+            .next_back()
+            .map(|(&height, &hash)| BlockHeightAndHash { height, hash })
     }
 
     fn start_workers(&mut self) {
-        self.workers_finish.store(false, Ordering::SeqCst);
+        self.workers_stop = ShutdownHandle::new();
 
         let (tx, rx) = crossbeam_channel::bounded(self.thread_num * 64);
         self.rx = Some(rx);
@@ -127,13 +421,21 @@ where
                     let next_height = next_height.clone();
                     let rpc = self.rpc.clone();
                     let tx = tx.clone();
-                    let workers_finish = self.workers_finish.clone();
+                    let workers_stop = self.workers_stop.clone();
+                    let shutdown = self.shutdown.clone();
+                    let error = self.error.clone();
+                    let header_chain = self.header_chain.clone();
+                    let concurrency = self.concurrency.clone();
                     let in_progress = Arc::new(Mutex::new(Default::default()));
                     move || {
                         // TODO: constructor
                         let mut worker = Worker {
                             next_height,
-                            workers_finish,
+                            workers_stop,
+                            shutdown,
+                            error,
+                            header_chain,
+                            concurrency,
                             rpc,
                             tx,
                             in_progress,
@@ -197,60 +499,275 @@ where
         // this is how big reorgs we're going to detect
         let window_size = 1000;
         if self.cur_height >= window_size {
-            self.prev_hashes.remove(&(self.cur_height - window_size));
+            // after starting from a `ChainLocator` there might be multiple
+            // sparse entries below the window
+            while let Some(&h) = self.prev_hashes.keys().next() {
+                if self.cur_height - window_size < h {
+                    break;
+                }
+                self.prev_hashes.remove(&h);
+            }
         }
         assert!(self.prev_hashes.len() <= window_size as usize);
 
         false
     }
 
+    /// Find the highest block we've returned that is still in the node's best chain
+    ///
+    /// Walks `prev_hashes` from the top, comparing them with the hashes
+    /// the node has at the same heights.
+    ///
+    /// Returns `None` if shutdown was requested in the meantime.
+    fn find_fork_point(&self) -> Option<BlockHeightAndHash> {
+        for (&height, hash) in self.prev_hashes.iter().rev() {
+            let node_hash = retry(&self.shutdown, || self.rpc.get_block_id_by_height(height))?;
+            trace!(
+                "Fork point check: ours {} =? node {:?} at {}H",
+                hash,
+                node_hash,
+                height
+            );
+            if node_hash.as_ref() == Some(hash) {
+                return Some(BlockHeightAndHash {
+                    height,
+                    hash: *hash,
+                });
+            }
+        }
+        panic!(
+            "Fetcher detected a reorg beyond acceptable depth. No common block since {}H",
+            self.prev_hashes
+                .keys()
+                .next()
+                .expect("At least one element")
+        );
+    }
+
     /// Handle condition detected by `detected_reorg`
     ///
-    /// Basically, stop all workers (discarding their work), adjust height and
-    /// start workers again.
+    /// Basically, stop all workers (discarding their work), find the fork point,
+    /// rewind to it and start workers again.
     ///
     /// This doesn't have to be blazing fast, so it isn't.
     fn reset_on_reorg(&mut self) {
+        self.stop_workers();
+        let fork_point = if let Some(fork_point) = self.find_fork_point() {
+            fork_point
+        } else {
+            // shutting down; workers stay stopped
+            return;
+        };
+        let disconnected: Vec<_> = self
+            .prev_hashes
+            .split_off(&(fork_point.height + 1))
+            .into_iter()
+            .map(|(height, hash)| BlockHeightAndHash { height, hash })
+            .collect();
+
         debug!(
             "Resetting on reorg from {}H to {}H",
             self.cur_height,
-            self.cur_height - 1
+            fork_point.height + 1
         );
-        self.stop_workers();
-        assert!(self.cur_height > 0);
-        self.cur_height -= 1;
+        if !disconnected.is_empty() {
+            warn!(
+                "Reorg detected at {}H {}; disconnected {} blocks: {}H..={}H",
+                fork_point.height,
+                fork_point.hash,
+                disconnected.len(),
+                disconnected[0].height,
+                disconnected[disconnected.len() - 1].height,
+            );
+
+            self.reorg = Some(match self.reorg.take() {
+                // another reorg before any new block was returned: merge them
+                Some(mut prev_reorg) if prev_reorg.fork_point.height + 1 == self.cur_height => {
+                    let mut all_disconnected = disconnected;
+                    all_disconnected.append(&mut prev_reorg.disconnected);
+                    Reorg {
+                        fork_point,
+                        disconnected: all_disconnected,
+                    }
+                }
+                _ => Reorg {
+                    fork_point,
+                    disconnected,
+                },
+            });
+        }
+
+        if let Some(header_chain) = self.header_chain.as_ref() {
+            header_chain
+                .write()
+                .expect("lock works")
+                .reset(Some(fork_point));
+        }
+
+        self.cur_height = fork_point.height + 1;
         self.start_workers();
     }
+
+    /// Handle reorgs detected by the header sync thread
+    ///
+    /// Returns `true` if the workers were restarted.
+    fn handle_header_reorg(&mut self) -> bool {
+        let reorg_at = if let Some(header_chain) = self.header_chain.as_ref() {
+            header_chain.write().expect("lock works").take_reorg()
+        } else {
+            None
+        };
+
+        match reorg_at {
+            // blocks we've already returned are affected
+            Some(height) if height < self.cur_height => {
+                self.reset_on_reorg();
+                true
+            }
+            // discard bodies of blocks that are not in the header chain anymore
+            Some(height) => {
+                debug!("Header chain reorg at {}H; restarting workers", height);
+                self.stop_workers();
+                self.start_workers();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Check the block at `cur_height` before returning it
+    ///
+    /// Returns `false` if a reorg was detected and the block has to be discarded.
+    fn check_block(&mut self, block: &WithHeightAndId<R::Data>) -> bool {
+        if self.handle_header_reorg() {
+            return false;
+        }
+        if self.track_reorgs(block) {
+            self.reset_on_reorg();
+            return false;
+        }
+        true
+    }
+
+    /// Mark the block at `cur_height` as returned
+    fn advance(&mut self) {
+        self.cur_height += 1;
+        if let Some(header_chain) = self.header_chain.as_ref() {
+            header_chain
+                .write()
+                .expect("lock works")
+                .trim(self.cur_height);
+        }
+    }
 }
 
 impl<R> Fetcher<R>
 where
     R: Rpc,
 {
+    /// Take the details of the last reorg detected since the last call
+    ///
+    /// Multiple reorgs detected without any block returned in between
+    /// are merged into one.
+    pub fn take_reorg(&mut self) -> Option<Reorg> {
+        self.reorg.take()
+    }
+
+    /// Export a [`ChainLocator`] of the blocks returned so far
+    ///
+    /// Persist it along with the indexed data, and use it in
+    /// [`Fetcher::from_locator`] to resume after a restart.
+    pub fn chain_locator(&self) -> ChainLocator {
+        ChainLocator::from_descending(
+            self.prev_hashes
+                .iter()
+                .rev()
+                .map(|(&height, &hash)| BlockHeightAndHash { height, hash }),
+        )
+    }
+
+    /// Current state of the adaptive concurrency control
+    pub fn concurrency_stats(&self) -> ConcurrencyStats {
+        self.concurrency.stats()
+    }
+
+    /// Height of the next block to be returned
+    pub(crate) fn next_height(&self) -> BlockHeight {
+        self.cur_height
+    }
+
+    pub(crate) fn rpc(&self) -> &Arc<R> {
+        &self.rpc
+    }
+
+    /// Take the error that made this `Fetcher` shut down, if any
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.lock().expect("lock works").take()
+    }
+
+    /// Get a handle that can be used to shut down this `Fetcher`
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Stop all the workers, discarding their work
+    ///
+    /// Workers stuck for longer than `STOP_WORKERS_TIMEOUT` (eg. in a hanging
+    /// rpc call) are detached, so this always finishes in bounded time.
     fn stop_workers(&mut self) {
-        self.workers_finish.store(true, Ordering::SeqCst);
+        self.workers_stop.shutdown();
 
-        while let Ok(_) = self
-            .rx
-            .as_ref()
-            .expect("start_workers called before stop_workers")
-            .recv()
-        {}
+        let rx = if let Some(rx) = self.rx.take() {
+            rx
+        } else {
+            return;
+        };
 
-        self.rx = None;
-        self.thread_joins.drain(..).map(|j| j.join()).for_each(drop);
+        let deadline = Instant::now() + STOP_WORKERS_TIMEOUT;
+        loop {
+            match rx.recv_deadline(deadline) {
+                Ok(_) => {}
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                    self.thread_joins.drain(..).map(|j| j.join()).for_each(drop);
+                    break;
+                }
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    warn!(
+                        "Fetcher workers did not stop in {:?}; detaching them",
+                        STOP_WORKERS_TIMEOUT
+                    );
+                    self.thread_joins.clear();
+                    break;
+                }
+            }
+        }
         self.out_of_order_items.clear();
     }
 }
 
-impl<R> Iterator for Fetcher<R>
+impl<R> Fetcher<R>
 where
     R: Rpc + 'static,
     R::Data: WithPrevBlockHash,
 {
-    type Item = WithHeightAndId<R::Data>;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.end_of_fast_sync == self.cur_height {
+    /// Get the next block, waiting for it at most `timeout`
+    ///
+    /// Returns `Poll::Pending` if no block arrived in time (eg. because
+    /// the node has no new blocks), and `Poll::Ready(None)` after shutdown.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Poll<Option<WithHeightAndId<R::Data>>> {
+        self.next_until(Some(Instant::now() + timeout))
+    }
+
+    fn next_until(&mut self, deadline: Option<Instant>) -> Poll<Option<WithHeightAndId<R::Data>>> {
+        if self.shutdown.is_shutdown() {
+            return Poll::Ready(None);
+        }
+
+        if !self.started {
+            self.start();
+        }
+
+        if 1 < self.thread_num && self.end_of_fast_sync == self.cur_height {
             debug!(
                 "Fetcher: end of fast sync at {}H; switching to one worker",
                 self.cur_height
@@ -260,14 +777,22 @@ where
             self.start_workers();
         }
 
+        let timeout = deadline.map_or_else(crossbeam_channel::never, crossbeam_channel::at);
+        let header_reorg = self
+            .header_reorg_rx
+            .clone()
+            .unwrap_or_else(crossbeam_channel::never);
+
         'retry_on_reorg: loop {
             if let Some(item) = self.out_of_order_items.remove(&self.cur_height) {
-                if self.track_reorgs(&item) {
-                    self.reset_on_reorg();
+                if !self.check_block(&item) {
+                    if self.shutdown.is_shutdown() {
+                        return Poll::Ready(None);
+                    }
                     continue 'retry_on_reorg;
                 }
-                self.cur_height += 1;
-                return Some(item);
+                self.advance();
+                return Poll::Ready(Some(item));
             }
 
             loop {
@@ -275,20 +800,39 @@ where
                     "Waiting for the block from the workers at: {}H",
                     self.cur_height
                 );
-                let item = self
-                    .rx
-                    .as_ref()
-                    .expect("rx available")
-                    .recv()
-                    .expect("Workers shouldn't disconnect");
+                let item = crossbeam_channel::select! {
+                    recv(self.rx.as_ref().expect("rx available")) -> item => match item {
+                        Ok(item) => Some(item),
+                        // workers disconnect only on shutdown
+                        Err(_) => return Poll::Ready(None),
+                    },
+                    // no block might ever arrive to notice it otherwise,
+                    // eg. when the header chain can't be rolled back
+                    recv(header_reorg) -> _ => None,
+                    recv(self.shutdown.receiver()) -> _ => return Poll::Ready(None),
+                    recv(timeout) -> _ => return Poll::Pending,
+                };
+                let item = if let Some(item) = item {
+                    item
+                } else {
+                    if self.handle_header_reorg() {
+                        if self.shutdown.is_shutdown() {
+                            return Poll::Ready(None);
+                        }
+                        continue 'retry_on_reorg;
+                    }
+                    continue;
+                };
                 trace!("Got the block from the workers from: {}H", item.height);
                 if item.height == self.cur_height {
-                    if self.track_reorgs(&item) {
-                        self.reset_on_reorg();
+                    if !self.check_block(&item) {
+                        if self.shutdown.is_shutdown() {
+                            return Poll::Ready(None);
+                        }
                         continue 'retry_on_reorg;
                     }
-                    self.cur_height += 1;
-                    return Some(item);
+                    self.advance();
+                    return Poll::Ready(Some(item));
                 } else {
                     assert!(item.height > self.cur_height);
                     self.out_of_order_items.insert(item.height, item);
@@ -298,15 +842,42 @@ where
     }
 }
 
+impl<R> Iterator for Fetcher<R>
+where
+    R: Rpc + 'static,
+    R::Data: WithPrevBlockHash,
+{
+    type Item = WithHeightAndId<R::Data>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_until(None) {
+            Poll::Ready(item) => item,
+            Poll::Pending => unreachable!("no deadline"),
+        }
+    }
+}
+
 impl<R> Drop for Fetcher<R>
 where
     R: Rpc,
 {
     fn drop(&mut self) {
+        self.header_sync_stop.shutdown();
         self.stop_workers();
     }
 }
 
+/// Outcome of a worker's attempt to fetch the block at a height
+enum Fetched<D> {
+    Block(WithHeightAndId<D>),
+    /// No block at this height yet
+    ///
+    /// In headers-first mode, with a receiver disconnected once the header
+    /// chain changes.
+    NotYet(Option<crossbeam_channel::Receiver<()>>),
+    /// The node knows the block, but doesn't have its body (anymore)
+    NoBody,
+}
+
 /// One worker thread, polling for data from the node
 struct Worker<R>
 where
@@ -315,7 +886,12 @@ where
 {
     rpc: Arc<R>,
     next_height: Arc<AtomicUsize>,
-    workers_finish: Arc<AtomicBool>,
+    workers_stop: ShutdownHandle,
+    shutdown: ShutdownHandle,
+    error: Arc<Mutex<Option<anyhow::Error>>>,
+    /// Hashes to fetch, in headers-first mode
+    header_chain: Option<SharedHeaderChain>,
+    concurrency: Arc<Concurrency>,
     tx: crossbeam_channel::Sender<WithHeightAndId<R::Data>>,
     in_progress: Arc<Mutex<BTreeSet<BlockHeight>>>,
 }
@@ -327,35 +903,75 @@ where
 {
     fn run(&mut self) {
         loop {
+            let _permit = if let Some(permit) = self.acquire_permit() {
+                permit
+            } else {
+                return;
+            };
             let height = self.get_height_to_fetch();
 
             let mut retry_count = 0;
             'retry: loop {
-                if self.workers_finish.load(Ordering::SeqCst) {
+                if self.should_stop() {
                     return;
                 }
 
+                let request_start = Instant::now();
                 match self.get_block_by_height(height) {
                     Err(e) => {
                         trace!("Error from the node: {}", e);
+                        self.concurrency.record_error();
                         let ahead_minimum = height
                             - self
                                 .get_min_height_in_progress()
                                 .expect("at least current height");
-                        std::thread::sleep(Duration::from_millis(
+                        if self.sleep(Duration::from_millis(
                             (1 + R::RECOMMENDED_ERROR_RETRY_DELAY_MS) * u64::from(ahead_minimum),
-                        ));
+                        )) {
+                            return;
+                        }
                         retry_count += 1;
                         if retry_count % 10 == 0 {
                             debug!("Worker retrying rpc error {} at {}H", e, height);
                         }
                     }
-                    Ok(None) => {
+                    Ok(Fetched::NotYet(Some(header_chain_changed))) => {
+                        if self.wait_for(&header_chain_changed) {
+                            return;
+                        }
+                    }
+                    Ok(Fetched::NotYet(None)) => {
                         let sleep_ms = R::RECOMMENDED_HEAD_RETRY_DELAY_MS;
-                        std::thread::sleep(Duration::from_millis(sleep_ms));
+                        if self.sleep(Duration::from_millis(sleep_ms)) {
+                            return;
+                        }
                     }
-                    Ok(Some(item)) => {
-                        self.tx.send(item).expect("Send must not fail");
+                    Ok(Fetched::NoBody) => {
+                        if let Some(prune_height) = self.pruned_above(height) {
+                            error!(
+                                "Node pruned {}H before it was fetched; shutting down",
+                                height
+                            );
+                            *self.error.lock().expect("lock works") = Some(format_err!(
+                                "Node pruned blocks up to {}H, before {}H was fetched",
+                                prune_height,
+                                height
+                            ));
+                            self.shutdown.shutdown();
+                            return;
+                        }
+                        // eg. reorged out in the meantime
+                        let sleep_ms = R::RECOMMENDED_HEAD_RETRY_DELAY_MS;
+                        if self.sleep(Duration::from_millis(sleep_ms)) {
+                            return;
+                        }
+                    }
+                    Ok(Fetched::Block(item)) => {
+                        self.concurrency.record_success(request_start.elapsed());
+                        if self.tx.send(item).is_err() {
+                            // `Fetcher` gave up waiting for us and detached
+                            return;
+                        }
                         self.mark_height_fetched(height);
                         break 'retry;
                     }
@@ -364,6 +980,58 @@ where
         }
     }
 
+    /// Wait for a permit to have a request in flight
+    ///
+    /// Returns `None` if the worker should stop.
+    fn acquire_permit(&self) -> Option<Permit> {
+        loop {
+            if let Some(permit) = self.concurrency.try_acquire() {
+                return Some(permit);
+            }
+            if self.sleep(PERMIT_POLL_DELAY) {
+                return None;
+            }
+        }
+    }
+
+    /// Check if the node pruned the block at `height`
+    ///
+    /// Called only when the node doesn't have a block body, not on every error,
+    /// so a struggling node isn't asked even more. Returns the current prune
+    /// height if it did.
+    fn pruned_above(&self, height: BlockHeight) -> Option<BlockHeight> {
+        match self.rpc.get_prune_height() {
+            Ok(Some(prune_height)) if height < prune_height => Some(prune_height),
+            _ => None,
+        }
+    }
+
+    fn should_stop(&self) -> bool {
+        self.workers_stop.is_shutdown() || self.shutdown.is_shutdown()
+    }
+
+    /// Sleep, waking up early if the worker should stop
+    ///
+    /// Returns `true` if the worker should stop.
+    fn sleep(&self, duration: Duration) -> bool {
+        crossbeam_channel::select! {
+            recv(self.workers_stop.receiver()) -> _ => true,
+            recv(self.shutdown.receiver()) -> _ => true,
+            default(duration) => false,
+        }
+    }
+
+    /// Wait until `rx` gets a message or is disconnected
+    ///
+    /// Returns `true` if the worker should stop.
+    fn wait_for(&self, rx: &crossbeam_channel::Receiver<()>) -> bool {
+        crossbeam_channel::select! {
+            recv(self.workers_stop.receiver()) -> _ => true,
+            recv(self.shutdown.receiver()) -> _ => true,
+            recv(rx) -> _ => false,
+        }
+    }
+
     fn get_height_to_fetch(&self) -> BlockHeight {
         let height = self.next_height.fetch_add(1, Ordering::SeqCst) as BlockHeight;
         self.in_progress
@@ -386,18 +1054,207 @@ where
             .remove(&height));
     }
 
-    fn get_block_by_height(
-        &mut self,
-        height: BlockHeight,
-    ) -> Result<Option<WithHeightAndId<R::Data>>> {
-        if let Some(id) = self.rpc.get_block_id_by_height(height)? {
-            Ok(self.rpc.get_block_by_id(&id)?.map(|block| WithHeightAndId {
+    fn get_block_by_height(&mut self, height: BlockHeight) -> Result<Fetched<R::Data>> {
+        let id = if let Some(header_chain) = self.header_chain.as_ref() {
+            let header_chain = header_chain.read().expect("lock works");
+            match header_chain.get(height) {
+                Some(id) => id,
+                None => return Ok(Fetched::NotYet(Some(header_chain.changed()))),
+            }
+        } else {
+            match self.rpc.get_block_id_by_height(height)? {
+                Some(id) => id,
+                None => return Ok(Fetched::NotYet(None)),
+            }
+        };
+        Ok(match self.rpc.get_block_by_id(&id)? {
+            Some(block) => Fetched::Block(WithHeightAndId {
                 height,
                 id,
                 data: block,
-            }))
-        } else {
-            Ok(None)
-        }
+            }),
+            None => Fetched::NoBody,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Fetcher, Reorg};
+    use crate::{concurrency::WINDOW_LEN, mock::MockRpc};
+    use block_iter_core::{BlockHeightAndHash, ChainLocator};
+    use std::{sync::Arc, task::Poll, time::Duration};
+
+    /// Take the next `n` blocks, failing instead of hanging
+    fn take(fetcher: &mut Fetcher<MockRpc>, n: usize) -> Vec<BlockHeightAndHash> {
+        (0..n)
+            .map(|_| match fetcher.next_timeout(Duration::from_secs(10)) {
+                Poll::Ready(Some(block)) => BlockHeightAndHash {
+                    height: block.height,
+                    hash: block.id,
+                },
+                _ => panic!("no block from the fetcher"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn multi_block_reorg() {
+        let rpc = Arc::new(MockRpc::new(10));
+        let mut fetcher = Fetcher::new(rpc.clone(), None).unwrap();
+        assert_eq!(take(&mut fetcher, 10), rpc.chain(0..=9));
+        assert_eq!(fetcher.take_reorg(), None);
+
+        let disconnected = rpc.chain(7..=9);
+        rpc.reorg(6, 4);
+        assert_eq!(take(&mut fetcher, 4), rpc.chain(7..=10));
+        assert_eq!(
+            fetcher.take_reorg(),
+            Some(Reorg {
+                fork_point: rpc.chain(6..=6)[0],
+                disconnected,
+            })
+        );
+        assert_eq!(fetcher.take_reorg(), None);
+    }
+
+    #[test]
+    fn headers_first_tip_reorg() {
+        let rpc = Arc::new(MockRpc::new(5));
+        let mut fetcher = Fetcher::new(rpc.clone(), None).unwrap().headers_first(true);
+        assert_eq!(take(&mut fetcher, 5), rpc.chain(0..=4));
+        assert!(fetcher.next_timeout(Duration::from_millis(50)).is_pending());
+
+        // no block on top of the new tip, so the reorg can be noticed only
+        // by the header sync thread
+        let disconnected = rpc.chain(4..=4);
+        rpc.reorg(3, 1);
+        assert_eq!(take(&mut fetcher, 1), rpc.chain(4..=4));
+        assert_eq!(
+            fetcher.take_reorg(),
+            Some(Reorg {
+                fork_point: rpc.chain(3..=3)[0],
+                disconnected,
+            })
+        );
+
+        rpc.mine(2);
+        assert_eq!(take(&mut fetcher, 2), rpc.chain(5..=6));
+    }
+
+    #[test]
+    fn headers_first_multi_block_reorg() {
+        let rpc = Arc::new(MockRpc::new(10));
+        let mut fetcher = Fetcher::new(rpc.clone(), None).unwrap().headers_first(true);
+        assert_eq!(take(&mut fetcher, 10), rpc.chain(0..=9));
+
+        let disconnected = rpc.chain(7..=9);
+        rpc.reorg(6, 4);
+        assert_eq!(take(&mut fetcher, 4), rpc.chain(7..=10));
+        assert_eq!(
+            fetcher.take_reorg(),
+            Some(Reorg {
+                fork_point: rpc.chain(6..=6)[0],
+                disconnected,
+            })
+        );
+    }
+
+    #[test]
+    fn backs_off_on_errors() {
+        let rpc = Arc::new(MockRpc::new(30));
+        let mut fetcher = Fetcher::new(rpc.clone(), None).unwrap();
+        assert_eq!(fetcher.concurrency_stats().limit, 8);
+
+        rpc.fail_next(WINDOW_LEN as usize);
+        assert_eq!(take(&mut fetcher, 30), rpc.chain(0..=29));
+        // 20 errors among the first 50 results: backed off in one of the
+        // two full windows, and could speed up by at most one after it
+        assert!(fetcher.concurrency_stats().limit < 8);
+    }
+
+    #[test]
+    fn pruned_while_fetching() {
+        let rpc = Arc::new(MockRpc::new(3));
+        let mut fetcher = Fetcher::new(rpc.clone(), None).unwrap();
+        assert_eq!(take(&mut fetcher, 3), rpc.chain(0..=2));
+
+        // errors unrelated to pruning don't make workers ask about it
+        let prune_height_calls = rpc.prune_height_calls();
+        rpc.fail_next(10);
+        rpc.mine(5);
+        assert_eq!(take(&mut fetcher, 5), rpc.chain(3..=7));
+        assert_eq!(rpc.prune_height_calls(), prune_height_calls);
+
+        rpc.prune(9);
+        rpc.mine(2);
+        assert!(fetcher.next().is_none());
+        assert!(fetcher.take_error().unwrap().to_string().contains("pruned"));
+    }
+
+    #[test]
+    fn resume_from_locator() {
+        let rpc = Arc::new(MockRpc::new(31));
+        let locator = ChainLocator::from_descending(rpc.chain(0..=30).into_iter().rev());
+
+        let mut fetcher = Fetcher::from_locator(rpc.clone(), &locator).unwrap();
+        assert_eq!(fetcher.take_reorg(), None);
+        rpc.mine(1);
+        assert_eq!(take(&mut fetcher, 1), rpc.chain(31..=31));
+        drop(fetcher);
+
+        // the last 10 blocks are all in the locator
+        let disconnected = rpc.chain(28..=30);
+        rpc.reorg(27, 4);
+        let mut fetcher = Fetcher::from_locator(rpc.clone(), &locator).unwrap();
+        assert_eq!(
+            fetcher.take_reorg(),
+            Some(Reorg {
+                fork_point: rpc.chain(27..=27)[0],
+                disconnected,
+            })
+        );
+        assert_eq!(take(&mut fetcher, 4), rpc.chain(28..=31));
+    }
+
+    #[test]
+    fn resume_from_locator_deep_reorg() {
+        let rpc = Arc::new(MockRpc::new(101));
+        let locator = ChainLocator::from_descending(rpc.chain(0..=100).into_iter().rev());
+        let old = rpc.chain(0..=100);
+
+        rpc.reorg(80, 21);
+        let mut fetcher = Fetcher::from_locator(rpc.clone(), &locator).unwrap();
+        // the locator has only 77H and 85H around the fork point at 80H,
+        // the blocks between them are found through the stale headers
+        assert_eq!(
+            fetcher.take_reorg(),
+            Some(Reorg {
+                fork_point: old[80],
+                disconnected: old[81..=100].to_vec(),
+            })
+        );
+        assert_eq!(take(&mut fetcher, 21), rpc.chain(81..=101));
+    }
+
+    #[test]
+    fn merged_reorgs() {
+        let rpc = Arc::new(MockRpc::new(10));
+        let mut fetcher = Fetcher::new(rpc.clone(), None).unwrap();
+        assert_eq!(take(&mut fetcher, 10), rpc.chain(0..=9));
+
+        let disconnected = rpc.chain(5..=9);
+        // 7H is requested first while looking for the fork point, and then
+        // by the restarted worker, which gets a block of yet another chain
+        rpc.reorg_on_call(7, 2, 4, 7);
+        rpc.reorg(6, 4);
+        assert_eq!(take(&mut fetcher, 7), rpc.chain(5..=11));
+        assert_eq!(
+            fetcher.take_reorg(),
+            Some(Reorg {
+                fork_point: rpc.chain(4..=4)[0],
+                disconnected,
+            })
+        );
     }
 }
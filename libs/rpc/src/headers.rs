@@ -0,0 +1,208 @@
+use crate::{Rpc, ShutdownHandle};
+use anyhow::Result;
+use block_iter_core::{BlockHash, BlockHeight, BlockHeightAndHash};
+use crossbeam_channel::{Receiver, Sender};
+use log::{debug, trace, warn};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+pub(crate) type SharedHeaderChain = Arc<RwLock<HeaderChain>>;
+
+/// Number of headers requested from the node at once
+const HEADERS_BATCH_LEN: u32 = 1000;
+
+/// Chain of block hashes with verified links, pulled ahead of block bodies
+///
+/// Used by the `Fetcher` in headers-first mode: workers download
+/// bodies by hashes from here, so all of them belong to one known chain.
+#[derive(Debug)]
+pub(crate) struct HeaderChain {
+    hashes: BTreeMap<BlockHeight, BlockHash>,
+    /// Lowest height at which the chain was changed by a reorg, since last `take_reorg`
+    reorg_at: Option<BlockHeight>,
+    /// Replaced on every change, which disconnects `changed_rx` and wakes up
+    /// everyone waiting on it
+    changed_tx: Sender<()>,
+    changed_rx: Receiver<()>,
+}
+
+impl HeaderChain {
+    /// Start the chain right after `base`, or at the genesis block if `None`
+    pub(crate) fn new(base: Option<BlockHeightAndHash>) -> Self {
+        let (changed_tx, changed_rx) = crossbeam_channel::bounded(0);
+        let mut s = Self {
+            hashes: BTreeMap::default(),
+            reorg_at: None,
+            changed_tx,
+            changed_rx,
+        };
+        s.reset(base);
+        s
+    }
+
+    /// Reset the chain to start right after `base`, or at the genesis block if `None`
+    pub(crate) fn reset(&mut self, base: Option<BlockHeightAndHash>) {
+        self.hashes.clear();
+        self.reorg_at = None;
+        if let Some(base) = base {
+            self.hashes.insert(base.height, base.hash);
+        }
+        self.notify_changed();
+    }
+
+    /// A receiver that gets disconnected on the next change of the chain
+    ///
+    /// Take it while still holding the lock used to find out the chain
+    /// doesn't have what's needed yet, so no change is missed.
+    pub(crate) fn changed(&self) -> Receiver<()> {
+        self.changed_rx.clone()
+    }
+
+    fn notify_changed(&mut self) {
+        let (changed_tx, changed_rx) = crossbeam_channel::bounded(0);
+        self.changed_tx = changed_tx;
+        self.changed_rx = changed_rx;
+    }
+
+    pub(crate) fn get(&self, height: BlockHeight) -> Option<BlockHash> {
+        self.hashes.get(&height).copied()
+    }
+
+    fn tip(&self) -> Option<(BlockHeight, BlockHash)> {
+        self.hashes
+            .iter()
+            .next_back()
+            .map(|(&height, &hash)| (height, hash))
+    }
+
+    /// Forget hashes below `height`, keeping the one right below it
+    pub(crate) fn trim(&mut self, height: BlockHeight) {
+        if 0 < height {
+            self.hashes = self.hashes.split_off(&(height - 1));
+        }
+    }
+
+    fn mark_reorg(&mut self, height: BlockHeight) {
+        self.reorg_at = Some(self.reorg_at.map_or(height, |h| h.min(height)));
+    }
+
+    pub(crate) fn take_reorg(&mut self) -> Option<BlockHeight> {
+        self.reorg_at.take()
+    }
+}
+
+/// A thread keeping the `HeaderChain` in sync with the node
+pub(crate) struct HeaderSync<R> {
+    pub(crate) rpc: Arc<R>,
+    pub(crate) chain: SharedHeaderChain,
+    /// Wakes up the `Fetcher` when a reorg is marked in the chain
+    pub(crate) reorg_tx: crossbeam_channel::Sender<()>,
+    pub(crate) stop: ShutdownHandle,
+    pub(crate) shutdown: ShutdownHandle,
+}
+
+impl<R> HeaderSync<R>
+where
+    R: Rpc,
+{
+    pub(crate) fn run(&self) {
+        let mut retry_count = 0;
+        loop {
+            if self.stop.is_shutdown() || self.shutdown.is_shutdown() {
+                return;
+            }
+
+            let sleep_ms = match self.step() {
+                Err(e) => {
+                    retry_count += 1;
+                    if retry_count % 10 == 0 {
+                        debug!("Header sync retrying rpc error {}", e);
+                    }
+                    R::RECOMMENDED_ERROR_RETRY_DELAY_MS
+                }
+                Ok(false) => R::RECOMMENDED_HEAD_RETRY_DELAY_MS,
+                Ok(true) => continue,
+            };
+
+            if self.sleep(Duration::from_millis(sleep_ms)) {
+                return;
+            }
+        }
+    }
+
+    fn sleep(&self, duration: Duration) -> bool {
+        crossbeam_channel::select! {
+            recv(self.stop.receiver()) -> _ => true,
+            recv(self.shutdown.receiver()) -> _ => true,
+            default(duration) => false,
+        }
+    }
+
+    /// Tell the `Fetcher` that the chain was changed by a reorg
+    fn notify_reorg(&self) {
+        // a pending notification is as good as a new one
+        let _ = self.reorg_tx.try_send(());
+    }
+
+    /// Extend the chain by a batch of headers, or roll back one header on reorg
+    ///
+    /// Returns `false` if there's nothing to do right now.
+    fn step(&self) -> Result<bool> {
+        let tip = self.chain.read().expect("lock works").tip();
+        let height = tip.map(|(h, _)| h + 1).unwrap_or(0);
+
+        let headers = self.rpc.get_block_headers(height, HEADERS_BATCH_LEN)?;
+        let tip_replaced = match (tip, headers.first()) {
+            (Some((_, tip_hash)), Some(first)) => first.prev_blockhash != tip_hash,
+            // nothing new, but the tip itself could have been replaced
+            (Some((tip_height, tip_hash)), None) => {
+                self.rpc.get_block_id_by_height(tip_height)? != Some(tip_hash)
+            }
+            (None, _) => false,
+        };
+        if !tip_replaced && headers.is_empty() {
+            return Ok(false);
+        }
+
+        let mut chain = self.chain.write().expect("lock works");
+        if chain.tip() != tip {
+            // changed by the `Fetcher` in the meantime
+            return Ok(true);
+        }
+
+        if tip_replaced {
+            let (tip_height, tip_hash) = tip.expect("checked above");
+            // our tip is not in the node's best chain anymore
+            chain.mark_reorg(tip_height);
+            self.notify_reorg();
+            if chain.hashes.len() == 1 {
+                // reorg deeper than the chain; the `Fetcher` has to find
+                // the fork point and reset us
+                warn!("Header sync: reorg below {}H", tip_height);
+                return Ok(false);
+            }
+            debug!("Header sync: rolling back {}H {}", tip_height, tip_hash);
+            chain.hashes.remove(&tip_height);
+            chain.notify_changed();
+            return Ok(true);
+        }
+
+        let mut prev_hash = tip.map(|(_, hash)| hash);
+        for (header, height) in headers.iter().zip(height..) {
+            if prev_hash.map_or(false, |prev_hash| header.prev_blockhash != prev_hash) {
+                // the node switched chains while answering; the next step
+                // will roll back what doesn't link anymore
+                break;
+            }
+            let hash = header.block_hash();
+            trace!("Header sync: {}H {}", height, hash);
+            chain.hashes.insert(height, hash);
+            prev_hash = Some(hash);
+        }
+        chain.notify_changed();
+        Ok(true)
+    }
+}
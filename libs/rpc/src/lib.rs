@@ -1,9 +1,22 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, format_err, Result};
 use bitcoincore_rpc::RpcApi;
-use block_iter_core::{bitcoin, BlockHash, BlockHeight};
+use block_iter_core::{
+    bitcoin, bitcoin::hashes::hex::FromHex, bitcoin::BlockHeader, BlockHash, BlockHeight,
+};
 
+mod concurrency;
 mod fetcher;
-pub use fetcher::Fetcher;
+mod headers;
+mod mempool;
+#[cfg(test)]
+mod mock;
+mod prevouts;
+mod shutdown;
+pub use concurrency::ConcurrencyStats;
+pub use fetcher::{Fetcher, Reorg};
+pub use mempool::{MempoolEvent, MempoolFetcher, MempoolRpc, RemovalReason};
+pub use prevouts::{BlockWithPrevouts, WithPrevouts};
+pub use shutdown::ShutdownHandle;
 
 /// An minimum interface for node rpc for fetching blocks
 pub trait Rpc: Send + Sync {
@@ -16,7 +29,48 @@ pub trait Rpc: Send + Sync {
     fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<BlockHash>>;
 
     /// Get the block by id, along with id of the previous block
+    ///
+    /// Returns `None` if the node doesn't have it, eg. because it was pruned.
     fn get_block_by_id(&self, hash: &BlockHash) -> Result<Option<Self::Data>>;
+
+    /// Get the header of a block by id
+    fn get_block_header_by_id(&self, hash: &BlockHash) -> Result<Option<BlockHeader>>;
+
+    /// Get headers of the best chain at heights `start..start + count`
+    ///
+    /// Stops early at the tip of the chain. The default implementation makes two
+    /// calls per header, so override it if the node can batch requests.
+    fn get_block_headers(&self, start: BlockHeight, count: u32) -> Result<Vec<BlockHeader>> {
+        let mut headers = vec![];
+        for height in start..start.saturating_add(count) {
+            let hash = if let Some(hash) = self.get_block_id_by_height(height)? {
+                hash
+            } else {
+                break;
+            };
+            let header = if let Some(header) = self.get_block_header_by_id(&hash)? {
+                header
+            } else {
+                break;
+            };
+            if header.block_hash() != hash {
+                bail!(
+                    "Node returned a header of {} when asked for {}",
+                    header.block_hash(),
+                    hash
+                );
+            }
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    /// Get the height of the lowest block `get_block_by_id` can still return
+    ///
+    /// `None` if the node is not pruned.
+    fn get_prune_height(&self) -> Result<Option<BlockHeight>> {
+        Ok(None)
+    }
 }
 
 impl Rpc for bitcoincore_rpc::Client {
@@ -44,7 +98,8 @@ impl Rpc for bitcoincore_rpc::Client {
     fn get_block_by_id(&self, hash: &BlockHash) -> Result<Option<Self::Data>> {
         let block: bitcoin::Block = match self.get_by_id(hash) {
             Err(e) => {
-                if e.to_string().contains("Block height out of range") {
+                let e_str = e.to_string();
+                if e_str.contains("Block height out of range") || e_str.contains("pruned data") {
                     return Ok(None);
                 } else {
                     return Err(e.into());
@@ -55,6 +110,133 @@ impl Rpc for bitcoincore_rpc::Client {
 
         Ok(Some(block))
     }
+
+    fn get_block_header_by_id(&self, hash: &BlockHash) -> Result<Option<BlockHeader>> {
+        match RpcApi::get_block_header(self, hash) {
+            Err(e) => {
+                if e.to_string().contains("Block not found") {
+                    Ok(None)
+                } else {
+                    Err(e.into())
+                }
+            }
+            Ok(o) => Ok(Some(o)),
+        }
+    }
+
+    fn get_block_headers(&self, start: BlockHeight, count: u32) -> Result<Vec<BlockHeader>> {
+        let heights = (start..start.saturating_add(count))
+            .map(|height| Ok(vec![serde_json::value::to_raw_value(&height)?]))
+            .collect::<Result<Vec<_>>>()?;
+        let hashes: Vec<BlockHash> = call_batch(self, "getblockhash", &heights)?;
+
+        let hashes_params = hashes
+            .iter()
+            .map(|hash| {
+                Ok(vec![
+                    serde_json::value::to_raw_value(hash)?,
+                    serde_json::value::to_raw_value(&false)?,
+                ])
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let headers_hex: Vec<String> = call_batch(self, "getblockheader", &hashes_params)?;
+
+        let mut headers = Vec::with_capacity(headers_hex.len());
+        for (hex, hash) in headers_hex.iter().zip(hashes.iter()) {
+            let header: BlockHeader =
+                bitcoin::consensus::encode::deserialize(&Vec::<u8>::from_hex(hex)?)?;
+            if header.block_hash() != *hash {
+                bail!(
+                    "Node returned a header of {} when asked for {}",
+                    header.block_hash(),
+                    hash
+                );
+            }
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    fn get_prune_height(&self) -> Result<Option<BlockHeight>> {
+        let info = self.get_blockchain_info()?;
+        Ok(if info.pruned {
+            info.prune_height.map(|h| h as BlockHeight)
+        } else {
+            None
+        })
+    }
+}
+
+/// Make the same call with each of `params` in one batch request
+///
+/// Stops at the first call failing because the block is not there,
+/// e.g. past the tip of the chain.
+fn call_batch<T: serde::de::DeserializeOwned>(
+    client: &bitcoincore_rpc::Client,
+    method: &str,
+    params: &[Vec<Box<serde_json::value::RawValue>>],
+) -> Result<Vec<T>> {
+    if params.is_empty() {
+        return Ok(vec![]);
+    }
+    let jsonrpc = client.get_jsonrpc_client();
+    let requests: Vec<_> = params
+        .iter()
+        .map(|params| jsonrpc.build_request(method, params))
+        .collect();
+
+    let mut results = Vec::with_capacity(params.len());
+    for response in jsonrpc.send_batch(&requests)? {
+        let response =
+            response.ok_or_else(|| format_err!("No response to {} in a batch", method))?;
+        match response.result::<T>() {
+            Ok(result) => results.push(result),
+            Err(e) => {
+                let e = e.to_string();
+                if e.contains("Block height out of range") || e.contains("Block not found") {
+                    break;
+                } else {
+                    bail!("{} failed: {}", method, e);
+                }
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// An adapter making `Rpc` return only block headers as `Data`
+///
+/// `Fetcher<HeadersOnly<R>>` yields `WithHeightAndId<BlockHeader>`
+/// without ever downloading block bodies.
+pub struct HeadersOnly<R>(pub R);
+
+impl<R> Rpc for HeadersOnly<R>
+where
+    R: Rpc,
+{
+    type Data = BlockHeader;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = R::RECOMMENDED_HEAD_RETRY_DELAY_MS;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = R::RECOMMENDED_ERROR_RETRY_DELAY_MS;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        self.0.get_block_count()
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<BlockHash>> {
+        self.0.get_block_id_by_height(height)
+    }
+
+    fn get_block_by_id(&self, hash: &BlockHash) -> Result<Option<Self::Data>> {
+        self.0.get_block_header_by_id(hash)
+    }
+
+    fn get_block_header_by_id(&self, hash: &BlockHash) -> Result<Option<BlockHeader>> {
+        self.0.get_block_header_by_id(hash)
+    }
+
+    fn get_block_headers(&self, start: BlockHeight, count: u32) -> Result<Vec<BlockHeader>> {
+        self.0.get_block_headers(start, count)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -0,0 +1,507 @@
+use crate::{Fetcher, Reorg, Rpc};
+use anyhow::Result;
+use bitcoincore_rpc::RpcApi;
+use block_iter_core::{
+    bitcoin::{self, OutPoint, Transaction, Txid},
+    BlockHash, BlockHeight, BlockHeightAndHash, WithHeightAndId,
+};
+use log::{debug, trace, warn};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+/// How many of the latest blocks to remember the confirmed transactions of,
+/// to put them back in the mempool if the blocks get disconnected
+const REORG_WINDOW: BlockHeight = 100;
+
+/// Node rpc with access to the mempool
+pub trait MempoolRpc: Rpc {
+    /// Get ids of all the transactions in the mempool
+    fn get_raw_mempool(&self) -> Result<Vec<Txid>>;
+
+    /// Get a transaction from the mempool
+    ///
+    /// Returns `None` if it is not there (anymore).
+    fn get_mempool_transaction(&self, txid: &Txid) -> Result<Option<Transaction>>;
+}
+
+impl MempoolRpc for bitcoincore_rpc::Client {
+    fn get_raw_mempool(&self) -> Result<Vec<Txid>> {
+        Ok(RpcApi::get_raw_mempool(self)?)
+    }
+
+    fn get_mempool_transaction(&self, txid: &Txid) -> Result<Option<Transaction>> {
+        match RpcApi::get_raw_transaction(self, txid, None) {
+            Err(e) => {
+                if e.to_string()
+                    .contains("No such mempool or blockchain transaction")
+                {
+                    Ok(None)
+                } else {
+                    Err(e.into())
+                }
+            }
+            Ok(o) => Ok(Some(o)),
+        }
+    }
+}
+
+/// Why a transaction is not in the mempool anymore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalReason {
+    /// Included in a block returned before this event
+    Confirmed {
+        height: BlockHeight,
+        block: BlockHash,
+    },
+    /// Conflicting transaction spending the same output(s) replaced it
+    /// in the mempool, or got confirmed
+    Replaced { by: Txid },
+    /// Expired, evicted when the mempool was full, or removed for any other reason
+    Evicted,
+}
+
+#[derive(Debug)]
+pub enum MempoolEvent {
+    /// New block in the best chain, just like returned by the `Fetcher`
+    Block(WithHeightAndId<bitcoin::Block>),
+    TxAdded {
+        txid: Txid,
+        tx: Transaction,
+    },
+    TxRemoved {
+        txid: Txid,
+        reason: RemovalReason,
+    },
+    /// Blocks returned before were disconnected, see [`Reorg`]
+    ///
+    /// Followed by the transactions reported as confirmed in them being
+    /// added back, and by the blocks of the new chain.
+    Reorg {
+        fork_point: BlockHeightAndHash,
+        disconnected: Vec<BlockHeightAndHash>,
+    },
+}
+
+/// Blocks from a `Fetcher`, along with the changes to the node's mempool
+///
+/// The mempool is polled only once the `Fetcher` caught up with the node.
+/// Transactions that disappear from the mempool are reported as removed
+/// only after the `Fetcher` returned all the blocks the node had at the
+/// time, so a transaction included in a block is always reported as
+/// `Confirmed` right after the `Block` event including it. This way
+/// the consumer sees a consistent view of the chain and the mempool.
+///
+/// On a reorg, transactions reported as confirmed in the disconnected blocks
+/// are reported again as `TxAdded` right after the `Reorg` event, as if
+/// they were back in the mempool. If the new chain confirms them too,
+/// they are reported as `Confirmed` again, and if the node drops them
+/// instead, as `Evicted`.
+pub struct MempoolFetcher<R>
+where
+    R: MempoolRpc<Data = bitcoin::Block>,
+{
+    fetcher: Fetcher<R>,
+    poll_interval: Duration,
+    next_poll: Instant,
+    caught_up: bool,
+    /// Transactions in the mempool, as seen by the consumer
+    txs: HashMap<Txid, Transaction>,
+    /// Which transaction in `txs` spends a given output
+    spent_by: HashMap<OutPoint, Txid>,
+    /// Transactions gone from the node's mempool, waiting for the `Fetcher`
+    /// to return all the blocks up to the height the node was at
+    pending_removal: HashMap<Txid, BlockHeight>,
+    /// Transactions reported as confirmed in the latest blocks, by height
+    confirmed: BTreeMap<BlockHeight, Vec<(Txid, Transaction)>>,
+    events: VecDeque<MempoolEvent>,
+}
+
+impl<R> MempoolFetcher<R>
+where
+    R: MempoolRpc<Data = bitcoin::Block> + 'static,
+{
+    pub fn new(fetcher: Fetcher<R>) -> Self {
+        Self {
+            fetcher,
+            poll_interval: Duration::from_millis(R::RECOMMENDED_HEAD_RETRY_DELAY_MS),
+            next_poll: Instant::now(),
+            caught_up: false,
+            txs: Default::default(),
+            spent_by: Default::default(),
+            pending_removal: Default::default(),
+            confirmed: Default::default(),
+            events: Default::default(),
+        }
+    }
+
+    /// Set how often to poll the mempool
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    fn insert(&mut self, txid: Txid, tx: Transaction) {
+        for input in &tx.input {
+            self.spent_by.insert(input.previous_output, txid);
+        }
+        self.txs.insert(txid, tx);
+    }
+
+    fn remove(&mut self, txid: &Txid, reason: RemovalReason) -> Option<Transaction> {
+        let tx = self.txs.remove(txid)?;
+        self.pending_removal.remove(txid);
+        for input in &tx.input {
+            if self.spent_by.get(&input.previous_output) == Some(txid) {
+                self.spent_by.remove(&input.previous_output);
+            }
+        }
+        trace!("Mempool: removed {} ({:?})", txid, reason);
+        self.events.push_back(MempoolEvent::TxRemoved {
+            txid: *txid,
+            reason,
+        });
+        Some(tx)
+    }
+
+    /// Remove transactions spending the same outputs as `tx`
+    fn remove_conflicts(&mut self, txid: Txid, tx: &Transaction) {
+        if tx.is_coin_base() {
+            return;
+        }
+        for input in &tx.input {
+            if let Some(&conflict) = self.spent_by.get(&input.previous_output) {
+                if conflict != txid {
+                    self.remove(&conflict, RemovalReason::Replaced { by: txid });
+                }
+            }
+        }
+    }
+
+    fn handle_block(&mut self, block: WithHeightAndId<bitcoin::Block>) {
+        let block_event_idx = self.events.len();
+        let mut confirmed = vec![];
+        for tx in &block.data.txdata {
+            let txid = tx.txid();
+            let reason = RemovalReason::Confirmed {
+                height: block.height,
+                block: block.id,
+            };
+            if let Some(tx) = self.remove(&txid, reason) {
+                confirmed.push((txid, tx));
+            }
+            self.remove_conflicts(txid, tx);
+        }
+
+        self.confirmed = self
+            .confirmed
+            .split_off(&block.height.saturating_sub(REORG_WINDOW - 1));
+        if !confirmed.is_empty() {
+            self.confirmed.insert(block.height, confirmed);
+        }
+
+        self.events
+            .insert(block_event_idx, MempoolEvent::Block(block));
+        self.evict_pending();
+    }
+
+    fn handle_reorg(&mut self, reorg: Reorg) {
+        let disconnected = self.confirmed.split_off(&(reorg.fork_point.height + 1));
+        self.events.push_back(MempoolEvent::Reorg {
+            fork_point: reorg.fork_point,
+            disconnected: reorg.disconnected,
+        });
+
+        for (txid, tx) in disconnected.into_values().flatten() {
+            self.remove_conflicts(txid, &tx);
+            trace!("Mempool: added back {}", txid);
+            self.insert(txid, tx.clone());
+            self.events.push_back(MempoolEvent::TxAdded { txid, tx });
+        }
+        // heights the node was at don't mean the same on the new chain, so
+        // check again with the next poll
+        self.pending_removal.clear();
+    }
+
+    /// Report removals we're sure were not caused by blocks
+    fn evict_pending(&mut self) {
+        let next_height = self.fetcher.next_height();
+        let evicted: Vec<_> = self
+            .pending_removal
+            .iter()
+            .filter(|(_, &node_height)| node_height < next_height)
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in evicted {
+            self.remove(&txid, RemovalReason::Evicted);
+        }
+    }
+
+    fn poll_mempool(&mut self) -> Result<()> {
+        let rpc = self.fetcher.rpc().clone();
+        let mempool = rpc.get_raw_mempool()?;
+        // any transaction that left the mempool before the snapshot
+        // was confirmed at this height or below, if at all
+        let node_height = rpc.get_block_count()?;
+
+        let in_mempool: HashSet<_> = mempool.iter().copied().collect();
+        for txid in self.txs.keys() {
+            if in_mempool.contains(txid) {
+                // back after a reorg
+                self.pending_removal.remove(txid);
+            } else {
+                self.pending_removal.entry(*txid).or_insert(node_height);
+            }
+        }
+
+        for txid in mempool {
+            if self.txs.contains_key(&txid) {
+                continue;
+            }
+            let tx = if let Some(tx) = rpc.get_mempool_transaction(&txid)? {
+                tx
+            } else {
+                // gone already; will be in one of the next blocks, if anything
+                continue;
+            };
+            self.remove_conflicts(txid, &tx);
+            trace!("Mempool: added {}", txid);
+            self.insert(txid, tx.clone());
+            self.events.push_back(MempoolEvent::TxAdded { txid, tx });
+        }
+
+        self.evict_pending();
+        debug!(
+            "Mempool: {} txs; {} pending removal",
+            self.txs.len(),
+            self.pending_removal.len()
+        );
+        Ok(())
+    }
+}
+
+impl<R> Iterator for MempoolFetcher<R>
+where
+    R: MempoolRpc<Data = bitcoin::Block> + 'static,
+{
+    type Item = MempoolEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Some(event);
+            }
+
+            let now = Instant::now();
+            if self.caught_up && self.next_poll <= now {
+                if let Err(e) = self.poll_mempool() {
+                    warn!("Mempool: polling failed: {}", e);
+                }
+                self.next_poll = now + self.poll_interval;
+                continue;
+            }
+
+            let timeout = if self.caught_up {
+                self.next_poll.saturating_duration_since(now)
+            } else {
+                self.poll_interval
+            };
+            let next = self.fetcher.next_timeout(timeout);
+            if let Some(reorg) = self.fetcher.take_reorg() {
+                self.handle_reorg(reorg);
+            }
+            match next {
+                Poll::Ready(Some(block)) => self.handle_block(block),
+                Poll::Ready(None) => return None,
+                Poll::Pending => self.caught_up = true,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MempoolEvent, MempoolFetcher, RemovalReason};
+    use crate::{mock::MockRpc, Fetcher};
+    use block_iter_core::{
+        bitcoin::{OutPoint, Transaction, TxIn, TxOut, Txid},
+        BlockHeight, BlockHeightAndHash,
+    };
+    use std::{sync::Arc, time::Duration};
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Event {
+        Block(BlockHeight),
+        Added(Txid),
+        Removed(Txid, RemovalReason),
+        Reorg(BlockHeightAndHash, Vec<BlockHeightAndHash>),
+    }
+
+    fn mempool_fetcher(rpc: &Arc<MockRpc>) -> MempoolFetcher<MockRpc> {
+        MempoolFetcher::new(Fetcher::new(rpc.clone(), None).unwrap())
+            .poll_interval(Duration::from_millis(10))
+    }
+
+    fn take(fetcher: &mut MempoolFetcher<MockRpc>, n: usize) -> Vec<Event> {
+        fetcher
+            .take(n)
+            .map(|event| match event {
+                MempoolEvent::Block(block) => Event::Block(block.height),
+                MempoolEvent::TxAdded { txid, .. } => Event::Added(txid),
+                MempoolEvent::TxRemoved { txid, reason } => Event::Removed(txid, reason),
+                MempoolEvent::Reorg {
+                    fork_point,
+                    disconnected,
+                } => Event::Reorg(fork_point, disconnected),
+            })
+            .collect()
+    }
+
+    /// A transaction spending the `vout` output of a fake transaction
+    fn tx(vout: u32, value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Default::default(),
+                    vout,
+                },
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value,
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[test]
+    fn confirmed() {
+        let rpc = Arc::new(MockRpc::new(1));
+        let (a, b) = (tx(0, 1000), tx(1, 1000));
+        rpc.add_to_mempool(a.clone());
+        rpc.add_to_mempool(b.clone());
+        let mut fetcher = mempool_fetcher(&rpc);
+        assert_eq!(
+            take(&mut fetcher, 3),
+            vec![
+                Event::Block(0),
+                Event::Added(a.txid()),
+                Event::Added(b.txid())
+            ]
+        );
+
+        // reported as confirmed right after the block, even if the mempool
+        // is polled before the fetcher gets the block
+        let block = rpc.mine_txs(vec![a.clone()]);
+        assert_eq!(
+            take(&mut fetcher, 2),
+            vec![
+                Event::Block(1),
+                Event::Removed(a.txid(), RemovalReason::Confirmed { height: 1, block })
+            ]
+        );
+
+        // still in the mempool, so nothing to report
+        rpc.mine(1);
+        assert_eq!(take(&mut fetcher, 1), vec![Event::Block(2)]);
+    }
+
+    #[test]
+    fn replaced() {
+        let rpc = Arc::new(MockRpc::new(1));
+        let mut fetcher = mempool_fetcher(&rpc);
+        assert_eq!(take(&mut fetcher, 1), vec![Event::Block(0)]);
+
+        // RBF in the mempool
+        let (a, a2) = (tx(0, 1000), tx(0, 900));
+        rpc.add_to_mempool(a.clone());
+        assert_eq!(take(&mut fetcher, 1), vec![Event::Added(a.txid())]);
+        rpc.add_to_mempool(a2.clone());
+        assert_eq!(
+            take(&mut fetcher, 2),
+            vec![
+                Event::Removed(a.txid(), RemovalReason::Replaced { by: a2.txid() }),
+                Event::Added(a2.txid()),
+            ]
+        );
+
+        // a conflicting transaction confirmed without ever being in the mempool
+        let a3 = tx(0, 800);
+        rpc.mine_txs(vec![a3.clone()]);
+        assert_eq!(
+            take(&mut fetcher, 2),
+            vec![
+                Event::Block(1),
+                Event::Removed(a2.txid(), RemovalReason::Replaced { by: a3.txid() }),
+            ]
+        );
+    }
+
+    #[test]
+    fn evicted() {
+        let rpc = Arc::new(MockRpc::new(1));
+        let a = tx(0, 1000);
+        rpc.add_to_mempool(a.clone());
+        let mut fetcher = mempool_fetcher(&rpc);
+        assert_eq!(
+            take(&mut fetcher, 2),
+            vec![Event::Block(0), Event::Added(a.txid())]
+        );
+
+        rpc.evict(&a.txid());
+        assert_eq!(
+            take(&mut fetcher, 1),
+            vec![Event::Removed(a.txid(), RemovalReason::Evicted)]
+        );
+
+        // not in the mempool anymore, so nothing to report on confirmation
+        rpc.mine_txs(vec![a]);
+        assert_eq!(take(&mut fetcher, 1), vec![Event::Block(1)]);
+    }
+
+    #[test]
+    fn confirmed_then_reorged() {
+        let rpc = Arc::new(MockRpc::new(1));
+        let a = tx(0, 1000);
+        rpc.add_to_mempool(a.clone());
+        let mut fetcher = mempool_fetcher(&rpc);
+        assert_eq!(
+            take(&mut fetcher, 2),
+            vec![Event::Block(0), Event::Added(a.txid())]
+        );
+
+        let block = rpc.mine_txs(vec![a.clone()]);
+        assert_eq!(
+            take(&mut fetcher, 2),
+            vec![
+                Event::Block(1),
+                Event::Removed(a.txid(), RemovalReason::Confirmed { height: 1, block })
+            ]
+        );
+
+        // the block is disconnected, and the transaction confirmed again
+        // one block higher on the new chain
+        let fork_point = rpc.chain(0..=0)[0];
+        let disconnected = rpc.chain(1..=1);
+        rpc.reorg(0, 1);
+        let block = rpc.mine_txs(vec![a.clone()]);
+        assert_eq!(
+            take(&mut fetcher, 5),
+            vec![
+                Event::Reorg(fork_point, disconnected),
+                Event::Added(a.txid()),
+                Event::Block(1),
+                Event::Block(2),
+                Event::Removed(a.txid(), RemovalReason::Confirmed { height: 2, block })
+            ]
+        );
+
+        // nothing left pending for it
+        rpc.mine(1);
+        assert_eq!(take(&mut fetcher, 1), vec![Event::Block(3)]);
+    }
+}
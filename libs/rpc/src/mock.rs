@@ -0,0 +1,245 @@
+//! A fake node, to test without `bitcoind`
+
+use crate::{MempoolRpc, Rpc};
+use anyhow::{bail, Result};
+use block_iter_core::{
+    bitcoin::{Block, BlockHeader, Transaction, TxIn},
+    BlockHash, BlockHeight, BlockHeightAndHash, Txid,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    sync::Mutex,
+};
+
+/// Reorg to perform when a block id is requested by height
+struct Trigger {
+    height: BlockHeight,
+    calls_left: usize,
+    fork_height: BlockHeight,
+    len: u32,
+}
+
+#[derive(Default)]
+struct State {
+    /// Best chain
+    chain: Vec<Block>,
+    /// Every block the node ever had, including stale ones
+    blocks: HashMap<BlockHash, Block>,
+    /// Number of reorgs so far, makes blocks of every fork different
+    forks: u32,
+    trigger: Option<Trigger>,
+    /// Number of the next `get_block_by_id` calls to fail
+    failures: usize,
+    /// In the order the transactions were added
+    mempool: Vec<Transaction>,
+    prune_height: Option<BlockHeight>,
+    prune_height_calls: usize,
+}
+
+impl State {
+    fn mine(&mut self, txdata: Vec<Transaction>) -> BlockHash {
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: self.chain.last().map(Block::block_hash).unwrap_or_default(),
+                merkle_root: Default::default(),
+                // the height, to tell which blocks are pruned
+                time: self.chain.len() as u32,
+                bits: 0x207fffff,
+                nonce: self.forks,
+            },
+            txdata,
+        };
+        let hash = block.block_hash();
+        self.blocks.insert(hash, block.clone());
+        self.chain.push(block);
+        hash
+    }
+
+    /// Remove transactions spending any of the outputs `tx` spends from the mempool
+    fn remove_conflicts(&mut self, tx: &Transaction) {
+        let spent: HashSet<_> = tx.input.iter().map(|input| input.previous_output).collect();
+        self.mempool.retain(|mempool_tx| {
+            !mempool_tx
+                .input
+                .iter()
+                .any(|input| spent.contains(&input.previous_output))
+        });
+    }
+
+    fn reorg(&mut self, fork_height: BlockHeight, len: u32) {
+        self.forks += 1;
+        self.chain.truncate(fork_height as usize + 1);
+        for _ in 0..len {
+            self.mine(vec![]);
+        }
+    }
+}
+
+/// A fake node, with a chain that can be extended and reorged at will
+#[derive(Default)]
+pub(crate) struct MockRpc {
+    state: Mutex<State>,
+}
+
+impl MockRpc {
+    /// Node with `len` empty blocks
+    pub(crate) fn new(len: u32) -> Self {
+        let rpc = Self::default();
+        rpc.mine(len);
+        rpc
+    }
+
+    /// Add `n` empty blocks on top of the chain
+    pub(crate) fn mine(&self, n: u32) {
+        let mut state = self.state.lock().expect("lock works");
+        for _ in 0..n {
+            state.mine(vec![]);
+        }
+    }
+
+    /// Replace the blocks above `fork_height` with `len` new ones
+    pub(crate) fn reorg(&self, fork_height: BlockHeight, len: u32) {
+        self.state
+            .lock()
+            .expect("lock works")
+            .reorg(fork_height, len);
+    }
+
+    /// Like [`MockRpc::reorg`], but only once the id of the block at `height`
+    /// is requested for the `nth` time from now
+    pub(crate) fn reorg_on_call(
+        &self,
+        height: BlockHeight,
+        nth: usize,
+        fork_height: BlockHeight,
+        len: u32,
+    ) {
+        self.state.lock().expect("lock works").trigger = Some(Trigger {
+            height,
+            calls_left: nth,
+            fork_height,
+            len,
+        });
+    }
+
+    /// Mine a block with a coinbase and `txs`
+    ///
+    /// Like a real node, `txs` and transactions conflicting with them leave the mempool.
+    pub(crate) fn mine_txs(&self, txs: Vec<Transaction>) -> BlockHash {
+        let mut state = self.state.lock().expect("lock works");
+        for tx in &txs {
+            state.remove_conflicts(tx);
+        }
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: state.chain.len() as u32,
+            input: vec![TxIn::default()],
+            output: vec![],
+        };
+        state.mine(std::iter::once(coinbase).chain(txs).collect())
+    }
+
+    /// Add `tx` to the mempool, replacing the transactions it conflicts with
+    pub(crate) fn add_to_mempool(&self, tx: Transaction) {
+        let mut state = self.state.lock().expect("lock works");
+        state.remove_conflicts(&tx);
+        state.mempool.push(tx);
+    }
+
+    /// Remove a transaction from the mempool, like when it expires
+    pub(crate) fn evict(&self, txid: &Txid) {
+        let mut state = self.state.lock().expect("lock works");
+        state.mempool.retain(|tx| &tx.txid() != txid);
+    }
+
+    /// Drop bodies of blocks below `height`, including the ones mined later
+    pub(crate) fn prune(&self, height: BlockHeight) {
+        self.state.lock().expect("lock works").prune_height = Some(height);
+    }
+
+    /// Number of `get_prune_height` calls so far
+    pub(crate) fn prune_height_calls(&self) -> usize {
+        self.state.lock().expect("lock works").prune_height_calls
+    }
+
+    /// Make the next `n` calls to `get_block_by_id` fail
+    pub(crate) fn fail_next(&self, n: usize) {
+        self.state.lock().expect("lock works").failures = n;
+    }
+
+    /// Blocks of the current chain at `heights`
+    pub(crate) fn chain(&self, heights: RangeInclusive<BlockHeight>) -> Vec<BlockHeightAndHash> {
+        let state = self.state.lock().expect("lock works");
+        heights
+            .map(|height| BlockHeightAndHash {
+                height,
+                hash: state.chain[height as usize].block_hash(),
+            })
+            .collect()
+    }
+}
+
+impl Rpc for MockRpc {
+    type Data = Block;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 10;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 1;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        let state = self.state.lock().expect("lock works");
+        Ok(state.chain.len().saturating_sub(1) as BlockHeight)
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<BlockHash>> {
+        let mut state = self.state.lock().expect("lock works");
+        let fire = match state.trigger.as_mut() {
+            Some(trigger) if trigger.height == height => {
+                trigger.calls_left -= 1;
+                trigger.calls_left == 0
+            }
+            _ => false,
+        };
+        if fire {
+            let trigger = state.trigger.take().expect("checked above");
+            state.reorg(trigger.fork_height, trigger.len);
+        }
+        Ok(state.chain.get(height as usize).map(Block::block_hash))
+    }
+
+    fn get_block_by_id(&self, hash: &BlockHash) -> Result<Option<Self::Data>> {
+        let mut state = self.state.lock().expect("lock works");
+        if 0 < state.failures {
+            state.failures -= 1;
+            bail!("Mock failure");
+        }
+        Ok(state
+            .blocks
+            .get(hash)
+            .filter(|block| block.header.time >= state.prune_height.unwrap_or(0))
+            .cloned())
+    }
+
+    fn get_block_header_by_id(&self, hash: &BlockHash) -> Result<Option<BlockHeader>> {
+        let state = self.state.lock().expect("lock works");
+        Ok(state.blocks.get(hash).map(|block| block.header))
+    }
+
+    fn get_prune_height(&self) -> Result<Option<BlockHeight>> {
+        let mut state = self.state.lock().expect("lock works");
+        state.prune_height_calls += 1;
+        Ok(state.prune_height)
+    }
+}
+
+impl MempoolRpc for MockRpc {
+    fn get_raw_mempool(&self) -> Result<Vec<Txid>> {
+        let state = self.state.lock().expect("lock works");
+        Ok(state.mempool.iter().map(Transaction::txid).collect())
+    }
+
+    fn get_mempool_transaction(&self, txid: &Txid) -> Result<Option<Transaction>> {
+        let state = self.state.lock().expect("lock works");
+        Ok(state.mempool.iter().find(|tx| &tx.txid() == txid).cloned())
+    }
+}
@@ -0,0 +1,241 @@
+use crate::Rpc;
+use anyhow::{bail, format_err, Result};
+use bitcoincore_rpc::RpcApi;
+use block_iter_core::{
+    bitcoin::{
+        self, consensus::encode, hashes::hex::FromHex, Amount, BlockHeader, OutPoint, Script,
+        Transaction, TxMerkleNode, TxOut,
+    },
+    BlockHash, BlockHeight, WithPrevBlockHash, WithTransactions,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// A block along with all the outputs spent by its transactions
+///
+/// `outpoint_values` is the same as in `BlockExtra` of the `block-iter` crate,
+/// so fees can be calculated for blocks fetched from the node too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockWithPrevouts {
+    pub block: bitcoin::Block,
+    pub outpoint_values: HashMap<OutPoint, TxOut>,
+}
+
+impl WithPrevBlockHash for BlockWithPrevouts {
+    fn prev_block_hash(&self) -> &BlockHash {
+        &self.block.header.prev_blockhash
+    }
+}
+
+impl WithTransactions for BlockWithPrevouts {
+    fn transactions(&self) -> &[bitcoin::Transaction] {
+        &self.block.txdata
+    }
+}
+
+/// An adapter making `Rpc` return [`BlockWithPrevouts`] as `Data`
+///
+/// Uses `getblock <hash> 3`, which requires bitcoind 23.0 or newer.
+pub struct WithPrevouts<R>(pub R);
+
+#[derive(Deserialize)]
+struct VerboseBlock {
+    hash: BlockHash,
+    version: i32,
+    previousblockhash: Option<BlockHash>,
+    merkleroot: TxMerkleNode,
+    time: u32,
+    bits: String,
+    nonce: u32,
+    tx: Vec<VerboseTx>,
+}
+
+#[derive(Deserialize)]
+struct VerboseTx {
+    hex: String,
+    vin: Vec<VerboseTxIn>,
+}
+
+#[derive(Deserialize)]
+struct VerboseTxIn {
+    prevout: Option<VerbosePrevout>,
+}
+
+#[derive(Deserialize)]
+struct VerbosePrevout {
+    value: f64,
+    #[serde(rename = "scriptPubKey")]
+    script_pubkey: VerboseScript,
+}
+
+#[derive(Deserialize)]
+struct VerboseScript {
+    hex: String,
+}
+
+impl VerboseBlock {
+    fn into_block_with_prevouts(self) -> Result<BlockWithPrevouts> {
+        let header = BlockHeader {
+            version: self.version,
+            prev_blockhash: self.previousblockhash.unwrap_or_default(),
+            merkle_root: self.merkleroot,
+            time: self.time,
+            bits: u32::from_str_radix(&self.bits, 16)?,
+            nonce: self.nonce,
+        };
+        if header.block_hash() != self.hash {
+            bail!(
+                "Header of block {} hashes to {}",
+                self.hash,
+                header.block_hash()
+            );
+        }
+
+        let mut txdata = Vec::with_capacity(self.tx.len());
+        let mut outpoint_values = HashMap::new();
+        for verbose_tx in self.tx {
+            let tx: Transaction = encode::deserialize(&Vec::<u8>::from_hex(&verbose_tx.hex)?)?;
+            if tx.input.len() != verbose_tx.vin.len() {
+                bail!("Inputs of tx {} don't match its hex", tx.txid());
+            }
+            if !tx.is_coin_base() {
+                for (input, verbose_input) in tx.input.iter().zip(verbose_tx.vin) {
+                    let prevout = verbose_input.prevout.ok_or_else(|| {
+                        format_err!(
+                            "No prevout of {} in getblock output; bitcoind 23.0 or newer required",
+                            input.previous_output
+                        )
+                    })?;
+                    outpoint_values.insert(
+                        input.previous_output,
+                        TxOut {
+                            value: Amount::from_btc(prevout.value)?.as_sat(),
+                            script_pubkey: Script::from(Vec::<u8>::from_hex(
+                                &prevout.script_pubkey.hex,
+                            )?),
+                        },
+                    );
+                }
+            }
+            txdata.push(tx);
+        }
+
+        Ok(BlockWithPrevouts {
+            block: bitcoin::Block { header, txdata },
+            outpoint_values,
+        })
+    }
+}
+
+impl Rpc for WithPrevouts<bitcoincore_rpc::Client> {
+    type Data = BlockWithPrevouts;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 =
+        <bitcoincore_rpc::Client as Rpc>::RECOMMENDED_HEAD_RETRY_DELAY_MS;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 =
+        <bitcoincore_rpc::Client as Rpc>::RECOMMENDED_ERROR_RETRY_DELAY_MS;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Rpc::get_block_count(&self.0)
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<BlockHash>> {
+        self.0.get_block_id_by_height(height)
+    }
+
+    fn get_block_by_id(&self, hash: &BlockHash) -> Result<Option<Self::Data>> {
+        let verbose: VerboseBlock = match RpcApi::call(
+            &self.0,
+            "getblock",
+            &[serde_json::to_value(hash)?, 3.into()],
+        ) {
+            Err(e) => {
+                let e_str = e.to_string();
+                if e_str.contains("Block not found") || e_str.contains("pruned data") {
+                    return Ok(None);
+                } else {
+                    return Err(e.into());
+                }
+            }
+            Ok(o) => o,
+        };
+
+        Ok(Some(verbose.into_block_with_prevouts()?))
+    }
+
+    fn get_block_header_by_id(&self, hash: &BlockHash) -> Result<Option<BlockHeader>> {
+        self.0.get_block_header_by_id(hash)
+    }
+
+    fn get_block_headers(&self, start: BlockHeight, count: u32) -> Result<Vec<BlockHeader>> {
+        self.0.get_block_headers(start, count)
+    }
+
+    fn get_prune_height(&self) -> Result<Option<BlockHeight>> {
+        self.0.get_prune_height()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::VerboseBlock;
+    use block_iter_core::{
+        bitcoin::{hashes::hex::FromHex, Script},
+        BlockHash, Txid,
+    };
+    use std::str::FromStr;
+
+    /// `getblock <hash> 3` of a regtest block with a coinbase, a transaction
+    /// with two inputs and one with a single input
+    const GETBLOCK_JSON: &str = include_str!("../test-data/getblock_verbosity_3.json");
+
+    #[test]
+    fn block_with_prevouts() {
+        let verbose: VerboseBlock = serde_json::from_str(GETBLOCK_JSON).unwrap();
+        let block = verbose.into_block_with_prevouts().unwrap();
+        assert_eq!(
+            block.block.block_hash(),
+            BlockHash::from_str("320d8cacd3d7a136fac260d747346a7b152332f955892f73c61107c7882c02cf")
+                .unwrap()
+        );
+        assert_eq!(block.block.txdata.len(), 3);
+        assert!(block.block.txdata[0].is_coin_base());
+        // the coinbase spends nothing
+        assert_eq!(block.outpoint_values.len(), 3);
+
+        let tx = &block.block.txdata[1];
+        assert_eq!(
+            tx.txid(),
+            Txid::from_str("966bd228bd2850dd2dee928f8cfd6b8c99883416ef75dbe9eb51bb2982336f3f")
+                .unwrap()
+        );
+        let prevouts: Vec<_> = tx
+            .input
+            .iter()
+            .map(|input| &block.outpoint_values[&input.previous_output])
+            .collect();
+        assert_eq!(prevouts[0].value, 125_000_000);
+        assert_eq!(prevouts[1].value, 50_000_000);
+        assert_eq!(
+            prevouts[0].script_pubkey,
+            Script::from(
+                Vec::<u8>::from_hex("76a914080808080808080808080808080808080808080888ac").unwrap()
+            )
+        );
+        let fee = prevouts.iter().map(|prevout| prevout.value).sum::<u64>()
+            - tx.output.iter().map(|output| output.value).sum::<u64>();
+        assert_eq!(fee, 10_000);
+
+        let tx = &block.block.txdata[2];
+        assert_eq!(
+            block.outpoint_values[&tx.input[0].previous_output].value,
+            12_345_678
+        );
+    }
+
+    #[test]
+    fn header_mismatch() {
+        let json = GETBLOCK_JSON.replace("\"nonce\": 3,", "\"nonce\": 4,");
+        let verbose: VerboseBlock = serde_json::from_str(&json).unwrap();
+        assert!(verbose.into_block_with_prevouts().is_err());
+    }
+}
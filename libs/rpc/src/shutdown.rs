@@ -0,0 +1,60 @@
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// A cloneable handle to request a shutdown
+///
+/// Nothing is ever sent over the underlying channel: shutting down
+/// drops the only `Sender`, which wakes up everyone waiting on
+/// the `Receiver` at once, including threads sleeping between retries.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    tx: Arc<Mutex<Option<Sender<()>>>>,
+    rx: Receiver<()>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        let (tx, rx) = crossbeam_channel::bounded(0);
+        Self {
+            tx: Arc::new(Mutex::new(Some(tx))),
+            rx,
+        }
+    }
+
+    /// Request a shutdown
+    ///
+    /// Can be called multiple times, and from any thread (e.g. a signal handler thread).
+    pub fn shutdown(&self) {
+        self.tx.lock().expect("lock works").take();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        matches!(self.rx.try_recv(), Err(TryRecvError::Disconnected))
+    }
+
+    /// Sleep for `duration`, or until shutdown is requested
+    ///
+    /// Returns `true` if shutdown was requested.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        matches!(
+            self.rx.recv_timeout(duration),
+            Err(RecvTimeoutError::Disconnected)
+        )
+    }
+
+    /// Receiver that becomes ready (disconnected) on shutdown
+    ///
+    /// Useful in `crossbeam_channel::select!`.
+    pub(crate) fn receiver(&self) -> &Receiver<()> {
+        &self.rx
+    }
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}